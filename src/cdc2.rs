@@ -0,0 +1,668 @@
+//! An implementation of the brain's CDC2 command protocol, which tools use to upload and run
+//! programs over the USB system port.
+//!
+//! Files are kept in memory, so they only last as long as the simulator is running.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    sync::mpsc,
+};
+
+use flate2::read::GzDecoder;
+
+use crate::{protocol::ProgramRequest, sdk::VexosVersion};
+
+/// The header at the start of every packet sent to the brain.
+const HOST_HEADER: [u8; 4] = [0xC9, 0x36, 0xB8, 0x47];
+/// The header at the start of every reply sent by the brain.
+const DEVICE_HEADER: [u8; 2] = [0xAA, 0x55];
+
+/// Simple command that asks for the VEXos version.
+const CMD_SYSTEM_VERSION: u8 = 0xA4;
+/// Command used to wrap every extended (CDC2) command.
+const CMD_EXTENDED: u8 = 0x56;
+
+// Extended commands
+const EXT_INIT_FILE_TRANSFER: u8 = 0x10;
+const EXT_EXIT_FILE_TRANSFER: u8 = 0x11;
+const EXT_WRITE_FILE: u8 = 0x12;
+const EXT_READ_FILE: u8 = 0x13;
+const EXT_LINK_FILE: u8 = 0x15;
+const EXT_DIRECTORY_FILE_COUNT: u8 = 0x16;
+const EXT_DIRECTORY_ENTRY: u8 = 0x17;
+const EXT_LOAD_FILE_ACTION: u8 = 0x18;
+const EXT_FILE_METADATA: u8 = 0x19;
+const EXT_ERASE_FILE: u8 = 0x1B;
+
+// Acknowledgements
+const ACK: u8 = 0x76;
+const NACK: u8 = 0xFF;
+const NACK_PACKET_CRC: u8 = 0xCE;
+const NACK_PACKET_LENGTH: u8 = 0xD0;
+const NACK_UNINITIALIZED_TRANSFER: u8 = 0xD4;
+const NACK_ADDRESS: u8 = 0xD7;
+const NACK_PROGRAM_FILE: u8 = 0xD3;
+
+// File transfer options
+const TRANSFER_WRITE: u8 = 1;
+const TRANSFER_READ: u8 = 2;
+const EXIT_ACTION_RUN: u8 = 1;
+const LOAD_ACTION_RUN: u8 = 0;
+const LOAD_ACTION_STOP: u8 = 0x80;
+
+/// The most bytes that can be sent in a single file transfer packet.
+const TRANSFER_WINDOW_SIZE: u16 = 4096;
+/// The largest file that can be uploaded, which is the size of a user program slot.
+const MAX_FILE_SIZE: u32 = 4 * 1024 * 1024;
+/// The largest a compressed program can be once it's inflated, which is the size of the user
+/// program region.
+const MAX_INFLATED_SIZE: u64 = 0x0480_0000;
+/// The first bytes of a gzip stream. Upload tools usually compress programs before sending them.
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+/// Length of the file name fields, which are NUL-padded.
+const FILE_NAME_LEN: usize = 24;
+
+/// Product ID of a V5 brain.
+const PRODUCT_V5_BRAIN: u8 = 0x10;
+
+/// Computes the CRC16 used by CDC2 packets (CRC-16/XMODEM).
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Appends a packet length, which takes two bytes with the high bit set if it's over 127.
+fn push_length(packet: &mut Vec<u8>, len: usize) {
+    if len > 0x7F {
+        packet.extend_from_slice(&(len as u16 | 0x8000).to_be_bytes());
+    } else {
+        packet.push(len as u8);
+    }
+}
+
+/// A file stored in the brain's flash.
+#[derive(Debug, Clone)]
+struct StoredFile {
+    data: Vec<u8>,
+    load_address: u32,
+    crc: u32,
+    file_type: [u8; 4],
+    timestamp: u32,
+    version: u32,
+}
+
+/// A file transfer that has been started with [`EXT_INIT_FILE_TRANSFER`].
+#[derive(Debug)]
+struct Transfer {
+    name: String,
+    writing: bool,
+    file: StoredFile,
+}
+
+/// Reads little-endian fields out of a packet's payload.
+struct PayloadReader<'a> {
+    payload: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.payload.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.payload.split_at(len);
+        self.payload = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn file_name(&mut self) -> Option<String> {
+        let name = self.bytes(FILE_NAME_LEN)?;
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        Some(String::from_utf8_lossy(&name[..len]).into_owned())
+    }
+}
+
+fn push_file_name(reply: &mut Vec<u8>, name: &str) {
+    let mut field = [0; FILE_NAME_LEN];
+    let len = name.len().min(FILE_NAME_LEN - 1);
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+    reply.extend_from_slice(&field);
+}
+
+/// The result of handling an extended command: an acknowledgement and the reply's data.
+type ExtReply = Result<Vec<u8>, u8>;
+
+/// The state of the brain as seen through the system port.
+pub struct SystemPort {
+    files: BTreeMap<String, StoredFile>,
+    transfer: Option<Transfer>,
+    requests: mpsc::Sender<ProgramRequest>,
+    version: VexosVersion,
+}
+
+impl SystemPort {
+    pub fn new(requests: mpsc::Sender<ProgramRequest>, version: VexosVersion) -> Self {
+        Self {
+            files: BTreeMap::new(),
+            transfer: None,
+            requests,
+            version,
+        }
+    }
+
+    /// Handles packets from the port until it is closed.
+    pub fn serve(mut self, mut port: impl Read + Write) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let len = port.read(&mut chunk)?;
+            if len == 0 {
+                return Ok(());
+            }
+            buffer.extend_from_slice(&chunk[..len]);
+
+            while let Some((packet_len, reply)) = self.handle_packet(&buffer) {
+                buffer.drain(..packet_len);
+                if let Some(reply) = reply {
+                    port.write_all(&reply)?;
+                    port.flush()?;
+                }
+            }
+        }
+    }
+
+    /// Tries to handle the packet at the start of the buffer.
+    ///
+    /// Returns `None` if the packet hasn't been fully received yet. Otherwise, returns how many bytes
+    /// of the buffer were used, and the reply to send, if any.
+    fn handle_packet(&mut self, buffer: &[u8]) -> Option<(usize, Option<Vec<u8>>)> {
+        // Skip over anything that isn't the start of a packet.
+        let Some(start) = buffer
+            .windows(HOST_HEADER.len())
+            .position(|window| window == HOST_HEADER)
+        else {
+            // Keep the end of the buffer in case it's the start of a header.
+            let keep = buffer.len().min(HOST_HEADER.len() - 1);
+            return (buffer.len() > keep).then(|| (buffer.len() - keep, None));
+        };
+        if start > 0 {
+            return Some((start, None));
+        }
+
+        let cmd = *buffer.get(HOST_HEADER.len())?;
+        match cmd {
+            CMD_SYSTEM_VERSION => {
+                let mut len = HOST_HEADER.len() + 1;
+                // Simple commands may be followed by an empty length.
+                if buffer.get(len) == Some(&0) {
+                    len += 1;
+                }
+                let version = self.version;
+                let payload = [
+                    version.major,
+                    version.minor,
+                    version.build,
+                    version.beta,
+                    PRODUCT_V5_BRAIN,
+                    0,
+                    0,
+                ];
+                Some((len, Some(simple_reply(cmd, &payload))))
+            }
+            CMD_EXTENDED => {
+                let ext_cmd = *buffer.get(HOST_HEADER.len() + 1)?;
+                let mut offset = HOST_HEADER.len() + 2;
+                let first = *buffer.get(offset)?;
+                let payload_len = if first & 0x80 != 0 {
+                    let second = *buffer.get(offset + 1)?;
+                    offset += 2;
+                    u16::from_be_bytes([first & 0x7F, second]) as usize
+                } else {
+                    offset += 1;
+                    first as usize
+                };
+                let packet_len = offset + payload_len + 2;
+                let packet = buffer.get(..packet_len)?;
+
+                let reply = if crc16(packet) != 0 {
+                    Err(NACK_PACKET_CRC)
+                } else {
+                    self.handle_extended(ext_cmd, &packet[offset..offset + payload_len])
+                };
+                Some((packet_len, Some(extended_reply(ext_cmd, reply))))
+            }
+            // Unknown simple commands can't be skipped reliably, so just drop the header.
+            _ => Some((HOST_HEADER.len() + 1, None)),
+        }
+    }
+
+    fn handle_extended(&mut self, ext_cmd: u8, payload: &[u8]) -> ExtReply {
+        let mut payload = PayloadReader { payload };
+        let reply = match ext_cmd {
+            EXT_INIT_FILE_TRANSFER => self.init_file_transfer(&mut payload),
+            EXT_EXIT_FILE_TRANSFER => self.exit_file_transfer(&mut payload),
+            EXT_WRITE_FILE => self.write_file(&mut payload),
+            EXT_READ_FILE => self.read_file(&mut payload),
+            // Linked files are only used by PROS's hot/cold linking, which doesn't apply to WASM.
+            EXT_LINK_FILE => Some(Ok(Vec::new())),
+            EXT_DIRECTORY_FILE_COUNT => {
+                let count = self.files.len() as u16;
+                Some(Ok(count.to_le_bytes().to_vec()))
+            }
+            EXT_DIRECTORY_ENTRY => self.directory_entry(&mut payload),
+            EXT_LOAD_FILE_ACTION => self.load_file_action(&mut payload),
+            EXT_FILE_METADATA => self.file_metadata(&mut payload),
+            EXT_ERASE_FILE => self.erase_file(&mut payload),
+            _ => Some(Err(NACK)),
+        };
+        reply.unwrap_or(Err(NACK_PACKET_LENGTH))
+    }
+
+    fn init_file_transfer(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let operation = payload.u8()?;
+        let _target = payload.u8()?;
+        let _vendor = payload.u8()?;
+        let _options = payload.u8()?;
+        let size = payload.u32()?;
+        let load_address = payload.u32()?;
+        let crc = payload.u32()?;
+        let file_type = payload.bytes(4)?.try_into().ok()?;
+        let timestamp = payload.u32()?;
+        let version = payload.u32()?;
+        let name = payload.file_name()?;
+
+        let file = match operation {
+            TRANSFER_WRITE if size > MAX_FILE_SIZE => return Some(Err(NACK)),
+            TRANSFER_WRITE => StoredFile {
+                data: vec![0; size as usize],
+                load_address,
+                crc,
+                file_type,
+                timestamp,
+                version,
+            },
+            TRANSFER_READ => match self.files.get(&name) {
+                Some(file) => file.clone(),
+                None => return Some(Err(NACK_PROGRAM_FILE)),
+            },
+            _ => return Some(Err(NACK)),
+        };
+
+        let mut reply = TRANSFER_WINDOW_SIZE.to_le_bytes().to_vec();
+        reply.extend_from_slice(&(file.data.len() as u32).to_le_bytes());
+        reply.extend_from_slice(&file.crc.to_le_bytes());
+        self.transfer = Some(Transfer {
+            name,
+            writing: operation == TRANSFER_WRITE,
+            file,
+        });
+        Some(Ok(reply))
+    }
+
+    fn exit_file_transfer(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let action = payload.u8()?;
+        let Some(transfer) = self.transfer.take() else {
+            return Some(Err(NACK_UNINITIALIZED_TRANSFER));
+        };
+        if transfer.writing {
+            let result = if action == EXIT_ACTION_RUN {
+                self.run(&transfer.name, &transfer.file)
+            } else {
+                Ok(())
+            };
+            self.files.insert(transfer.name, transfer.file);
+            if let Err(nack) = result {
+                return Some(Err(nack));
+            }
+        }
+        Some(Ok(Vec::new()))
+    }
+
+    fn write_file(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let address = payload.u32()?;
+        let data = payload.payload;
+        let Some(transfer) = self.transfer.as_mut().filter(|t| t.writing) else {
+            return Some(Err(NACK_UNINITIALIZED_TRANSFER));
+        };
+        let Some(dest) = address
+            .checked_sub(transfer.file.load_address)
+            .and_then(|offset| {
+                transfer
+                    .file
+                    .data
+                    .get_mut(offset as usize..)?
+                    .get_mut(..data.len())
+            })
+        else {
+            return Some(Err(NACK_ADDRESS));
+        };
+        dest.copy_from_slice(data);
+        Some(Ok(Vec::new()))
+    }
+
+    fn read_file(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let address = payload.u32()?;
+        let size = payload.u16()?;
+        let Some(transfer) = self.transfer.as_ref().filter(|t| !t.writing) else {
+            return Some(Err(NACK_UNINITIALIZED_TRANSFER));
+        };
+        let Some(offset) = address.checked_sub(transfer.file.load_address) else {
+            return Some(Err(NACK_ADDRESS));
+        };
+        let data = transfer
+            .file
+            .data
+            .get(offset as usize..)
+            .unwrap_or_default();
+        let data = &data[..data.len().min(size as usize)];
+
+        let mut reply = address.to_le_bytes().to_vec();
+        reply.extend_from_slice(data);
+        Some(Ok(reply))
+    }
+
+    fn directory_entry(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let index = payload.u8()?;
+        let Some((name, file)) = self.files.iter().nth(index as usize) else {
+            return Some(Err(NACK_PROGRAM_FILE));
+        };
+        let mut reply = vec![index];
+        push_metadata(&mut reply, file);
+        push_file_name(&mut reply, name);
+        Some(Ok(reply))
+    }
+
+    fn load_file_action(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let _vendor = payload.u8()?;
+        let action = payload.u8()?;
+        let name = payload.file_name()?;
+        match action {
+            LOAD_ACTION_RUN => {
+                let Some(file) = self.files.get(&name) else {
+                    return Some(Err(NACK_PROGRAM_FILE));
+                };
+                if let Err(nack) = self.run(&name, file) {
+                    return Some(Err(nack));
+                }
+            }
+            LOAD_ACTION_STOP => {
+                _ = self.requests.send(ProgramRequest::Stop);
+            }
+            _ => return Some(Err(NACK)),
+        }
+        Some(Ok(Vec::new()))
+    }
+
+    fn file_metadata(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let vendor = payload.u8()?;
+        let _reserved = payload.u8()?;
+        let name = payload.file_name()?;
+        let Some(file) = self.files.get(&name) else {
+            return Some(Err(NACK_PROGRAM_FILE));
+        };
+        let mut reply = vec![vendor];
+        push_metadata(&mut reply, file);
+        Some(Ok(reply))
+    }
+
+    fn erase_file(&mut self, payload: &mut PayloadReader) -> Option<ExtReply> {
+        let _vendor = payload.u8()?;
+        let _reserved = payload.u8()?;
+        let name = payload.file_name()?;
+        match self.files.remove(&name) {
+            Some(_) => Some(Ok(Vec::new())),
+            None => Some(Err(NACK_PROGRAM_FILE)),
+        }
+    }
+
+    /// Asks the simulator to run a file, if it's a program.
+    ///
+    /// Fails with a NACK code if the program is compressed and can't be inflated.
+    fn run(&self, name: &str, file: &StoredFile) -> Result<(), u8> {
+        // Programs are uploaded as `slot_N.bin`, next to an `.ini` file describing them.
+        if !name.ends_with(".bin") {
+            return Ok(());
+        }
+        let program = if file.data.starts_with(&GZIP_MAGIC) {
+            let mut program = Vec::new();
+            GzDecoder::new(&file.data[..])
+                .take(MAX_INFLATED_SIZE + 1)
+                .read_to_end(&mut program)
+                .map_err(|_| NACK)?;
+            if program.len() as u64 > MAX_INFLATED_SIZE {
+                return Err(NACK);
+            }
+            program
+        } else {
+            file.data.clone()
+        };
+        _ = self.requests.send(ProgramRequest::Run(program));
+        Ok(())
+    }
+}
+
+fn push_metadata(reply: &mut Vec<u8>, file: &StoredFile) {
+    reply.extend_from_slice(&(file.data.len() as u32).to_le_bytes());
+    reply.extend_from_slice(&file.load_address.to_le_bytes());
+    reply.extend_from_slice(&file.crc.to_le_bytes());
+    reply.extend_from_slice(&file.file_type);
+    reply.extend_from_slice(&file.timestamp.to_le_bytes());
+    reply.extend_from_slice(&file.version.to_le_bytes());
+}
+
+fn simple_reply(cmd: u8, payload: &[u8]) -> Vec<u8> {
+    let mut reply = DEVICE_HEADER.to_vec();
+    reply.push(cmd);
+    push_length(&mut reply, payload.len());
+    reply.extend_from_slice(payload);
+    reply
+}
+
+fn extended_reply(ext_cmd: u8, reply: ExtReply) -> Vec<u8> {
+    let (ack, data) = match reply {
+        Ok(data) => (ACK, data),
+        Err(nack) => (nack, Vec::new()),
+    };
+    let mut packet = DEVICE_HEADER.to_vec();
+    packet.push(CMD_EXTENDED);
+    // The length covers the extended command, the acknowledgement, the data, and the CRC.
+    push_length(&mut packet, data.len() + 4);
+    packet.push(ext_cmd);
+    packet.push(ack);
+    packet.extend_from_slice(&data);
+    let crc = crc16(&packet);
+    packet.extend_from_slice(&crc.to_be_bytes());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const LOAD_ADDRESS: u32 = 0x0380_0000;
+
+    fn port() -> (SystemPort, mpsc::Receiver<ProgramRequest>) {
+        let (requests, rx) = mpsc::channel();
+        (SystemPort::new(requests, VexosVersion::DEFAULT), rx)
+    }
+
+    /// Builds an extended command packet as a host would send it.
+    fn packet(ext_cmd: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = HOST_HEADER.to_vec();
+        packet.push(CMD_EXTENDED);
+        packet.push(ext_cmd);
+        push_length(&mut packet, payload.len());
+        packet.extend_from_slice(payload);
+        let crc = crc16(&packet);
+        packet.extend_from_slice(&crc.to_be_bytes());
+        packet
+    }
+
+    /// Sends an extended command, returning the acknowledgement and the reply's data.
+    fn send(port: &mut SystemPort, ext_cmd: u8, payload: &[u8]) -> (u8, Vec<u8>) {
+        let packet = packet(ext_cmd, payload);
+        let (len, reply) = port.handle_packet(&packet).unwrap();
+        assert_eq!(len, packet.len());
+        let reply = reply.unwrap();
+        assert_eq!(crc16(&reply), 0, "reply has a bad CRC");
+        let body = if reply[3] & 0x80 != 0 { 5 } else { 4 };
+        assert_eq!(reply[body], ext_cmd);
+        (reply[body + 1], reply[body + 2..reply.len() - 2].to_vec())
+    }
+
+    fn init_write(port: &mut SystemPort, name: &str, size: u32) -> u8 {
+        let mut payload = vec![TRANSFER_WRITE, 1, 1, 0];
+        payload.extend_from_slice(&size.to_le_bytes());
+        payload.extend_from_slice(&LOAD_ADDRESS.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        payload.extend_from_slice(b"bin\0");
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        push_file_name(&mut payload, name);
+        send(port, EXT_INIT_FILE_TRANSFER, &payload).0
+    }
+
+    /// Uploads a file in one chunk and runs it.
+    fn upload(port: &mut SystemPort, name: &str, data: &[u8]) -> u8 {
+        assert_eq!(init_write(port, name, data.len() as u32), ACK);
+        let mut payload = LOAD_ADDRESS.to_le_bytes().to_vec();
+        payload.extend_from_slice(data);
+        assert_eq!(send(port, EXT_WRITE_FILE, &payload).0, ACK);
+        send(port, EXT_EXIT_FILE_TRANSFER, &[EXIT_ACTION_RUN]).0
+    }
+
+    #[test]
+    fn crc16_matches_xmodem() {
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+
+        let mut data = b"123456789".to_vec();
+        data.extend_from_slice(&0x31C3u16.to_be_bytes());
+        assert_eq!(crc16(&data), 0);
+    }
+
+    #[test]
+    fn long_lengths_take_two_bytes() {
+        let mut packet = Vec::new();
+        push_length(&mut packet, 0x7F);
+        push_length(&mut packet, 0x80);
+        push_length(&mut packet, 0x1234);
+        assert_eq!(packet, [0x7F, 0x80, 0x80, 0x92, 0x34]);
+    }
+
+    #[test]
+    fn reports_system_version() {
+        let (mut port, _rx) = port();
+        let mut packet = HOST_HEADER.to_vec();
+        packet.extend_from_slice(&[CMD_SYSTEM_VERSION, 0]);
+        let (len, reply) = port.handle_packet(&packet).unwrap();
+        assert_eq!(len, packet.len());
+        let version = VexosVersion::DEFAULT;
+        assert_eq!(
+            reply.unwrap(),
+            [
+                0xAA,
+                0x55,
+                CMD_SYSTEM_VERSION,
+                7,
+                version.major,
+                version.minor,
+                version.build,
+                version.beta,
+                PRODUCT_V5_BRAIN,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn handles_partial_and_corrupt_packets() {
+        let (mut port, _rx) = port();
+        let packet = packet(EXT_DIRECTORY_FILE_COUNT, &[1, 0]);
+        assert!(port.handle_packet(&packet[..packet.len() - 1]).is_none());
+
+        // Junk before a packet is skipped.
+        let mut buffer = vec![0x00, 0x11];
+        buffer.extend_from_slice(&packet);
+        assert_eq!(port.handle_packet(&buffer), Some((2, None)));
+
+        let mut corrupt = packet.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let (_, reply) = port.handle_packet(&corrupt).unwrap();
+        assert_eq!(reply.unwrap()[5], NACK_PACKET_CRC);
+    }
+
+    #[test]
+    fn uploads_and_runs_programs() {
+        let (mut port, rx) = port();
+        assert_eq!(upload(&mut port, "slot_1.bin", b"\0asm program"), ACK);
+        let Ok(ProgramRequest::Run(program)) = rx.try_recv() else {
+            panic!("the program wasn't run");
+        };
+        assert_eq!(program, b"\0asm program");
+
+        assert_eq!(send(&mut port, EXT_DIRECTORY_FILE_COUNT, &[1, 0]).1, [1, 0]);
+
+        // Other files are stored but not run.
+        assert_eq!(upload(&mut port, "slot_1.ini", b"[program]"), ACK);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn inflates_compressed_programs() {
+        let (mut port, rx) = port();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"\0asm compressed").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(upload(&mut port, "slot_2.bin", &compressed), ACK);
+        let Ok(ProgramRequest::Run(program)) = rx.try_recv() else {
+            panic!("the program wasn't run");
+        };
+        assert_eq!(program, b"\0asm compressed");
+    }
+
+    #[test]
+    fn rejects_bad_uploads() {
+        let (mut port, rx) = port();
+        assert_eq!(init_write(&mut port, "slot_1.bin", MAX_FILE_SIZE + 1), NACK);
+
+        // A truncated gzip stream can't be inflated.
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0xAB; 1024]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(upload(&mut port, "slot_1.bin", &compressed[..20]), NACK);
+        assert!(rx.try_recv().is_err());
+
+        // Writes need a transfer to write to.
+        assert_eq!(
+            send(&mut port, EXT_WRITE_FILE, &LOAD_ADDRESS.to_le_bytes()).0,
+            NACK_UNINITIALIZED_TRANSFER
+        );
+    }
+}
@@ -1,33 +1,47 @@
-use std::{
-    path::{Path, PathBuf},
-    sync::mpsc,
-    thread,
-};
+use std::{ops::Range, path::PathBuf, sync::mpsc, thread, time::Duration};
 
 use anyhow::{anyhow, Context};
 use bytes::{Buf, Bytes};
 use clap::Parser as _;
 use fs_err as fs;
 
-use protocol::{Log, Protocol};
+use protocol::{Log, Protocol, ProtocolMode};
 use rgb::RGB8;
 use sdk::{
     display::{BLACK, WHITE},
-    SdlRequest,
+    link_wasi_stdio, parse_date, ClockMode, InputPlayback, InputRecorder, LinkKind, LinkModel,
+    SdCardFaults, SdkOptions, SdlRequest, SerialLink, SerialOverflow, SystemOptions, VexosVersion,
+    INITIAL_FUEL,
+};
+use sdl2::{
+    controller::{Axis, Button, GameController},
+    event::Event as SdlEvent,
 };
-use sdl2::controller::{Axis, Button};
 use vexide_simulator_protocol::{Command, ControllerState, Event, VCodeSig};
 use wasmparser::{Parser, Payload};
 use wasmtime::*;
 
-use crate::sdk::{JumpTable, SdkState};
+use crate::sdk::{check_memory_limit, Crash, ExitReason, JumpTable, ProgramExit, SdkState};
 
+#[cfg(unix)]
+mod cdc2;
 mod printf;
 mod protocol;
+#[cfg(unix)]
+mod pty;
 mod sdk;
 
 const HEADER_MAGIC: &[u8] = b"XVX5";
 
+/// How the simulator's exit code describes the way the program stopped.
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  The program returned from its entrypoint
+  1  The program crashed, or the simulator failed to run it
+  2  The program requested to exit with `vexSystemExitRequest`
+  3  The program was stopped from outside, e.g. by tapping the program header
+  4  The program was killed by the watchdog for not yielding";
+
 /// Execute WebAssembly programs that rely on the VEX V5 SDK and jump table.
 ///
 /// In order to be simulated, robot code should be WebAssembly-formatted (`.wasm`
@@ -36,16 +50,154 @@ const HEADER_MAGIC: &[u8] = b"XVX5";
 ///
 /// A WASI `preview1` environment is provided to allow C/C++ based programs to be run.
 #[derive(Debug, clap::Parser)]
-#[command(version)]
+#[command(version, after_help = EXIT_CODES_HELP)]
 struct Args {
     /// The path to the WebAssembly robot program that will be executed.
     program: PathBuf,
     /// Skip the protocol handshake and immediately start execution.
     #[clap(long, short = 'I')]
     imply_start: bool,
+    /// Run without a frontend, connecting the program's serial output and input to this terminal.
+    ///
+    /// Logs are printed to stderr. Implies `--imply-start`.
+    #[clap(long, short = 'T')]
+    terminal: bool,
+    /// Create pseudo-terminals that act like the brain's USB serial ports.
+    ///
+    /// Serial tools can attach to the user port, and upload tools can use the system port to
+    /// upload and run programs. Both paths are logged on startup.
+    #[clap(long)]
+    pty: bool,
     /// Fall back to the default code signature if the program's code signature is missing or invalid.
     #[clap(long, short = 'S')]
     relaxed_code_sig: bool,
+    /// Assign the first and second gamepads detected by SDL to the primary and partner controllers.
+    ///
+    /// Controller updates sent by the frontend are ignored in this mode.
+    #[clap(long, short = 'A')]
+    auto_controllers: bool,
+    /// How the controllers are connected to the brain.
+    #[clap(long, value_enum, default_value_t)]
+    controller_link: LinkKind,
+    /// Milliseconds between controller updates (defaults to 20 for VEXnet and 5 for tethered links).
+    #[clap(long, value_name = "MS")]
+    controller_interval: Option<u64>,
+    /// Milliseconds it takes for a controller update to reach the brain.
+    #[clap(long, value_name = "MS", default_value_t = 0)]
+    controller_latency: u64,
+    /// Disconnect the controllers between two times in milliseconds, e.g. `5000..5500`.
+    ///
+    /// May be specified multiple times.
+    #[clap(long, value_name = "START..END", value_parser = parse_millis_range)]
+    controller_dropout: Vec<Range<Duration>>,
+    /// Record the controller states seen by the program to a JSONL file.
+    #[clap(long, value_name = "FILE")]
+    record_inputs: Option<PathBuf>,
+    /// Replay a recording made with `--record-inputs` instead of using the real controllers.
+    #[clap(long, value_name = "FILE", conflicts_with = "record_inputs")]
+    replay_inputs: Option<PathBuf>,
+    /// Insert an SD card backed by the given directory.
+    ///
+    /// Without this, the card is absent unless the frontend inserts one.
+    #[clap(long, value_name = "DIR")]
+    usd: Option<PathBuf>,
+    /// Limit the SD card's size, so that writes are cut short once it is full.
+    ///
+    /// This only applies to the `vexFile*` APIs. Files written through libc aren't limited.
+    #[clap(long, value_name = "BYTES")]
+    usd_capacity: Option<u64>,
+    /// Simulate an SD card with its write-protect switch turned on.
+    #[clap(long)]
+    usd_read_only: bool,
+    /// Remove the SD card after the program has been running for some number of milliseconds.
+    ///
+    /// Only the `vexFile*` APIs see the card being removed. Files opened through libc stay usable.
+    #[clap(long, value_name = "MS")]
+    usd_eject_at: Option<u64>,
+    /// Limit the serial link to some number of bytes per second, like a real USB connection.
+    ///
+    /// Without this, serial output is sent as fast as the program writes it.
+    #[clap(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+    serial_bandwidth: Option<u32>,
+    /// What to do when the program writes serial output faster than the link can send it.
+    #[clap(long, value_enum, default_value_t)]
+    serial_overflow: SerialOverflow,
+    /// How many times faster than real time the simulation should run, e.g. `0.5` or `2`.
+    #[clap(long, value_name = "FACTOR", default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+    /// Run the simulation as fast as possible, instead of in real time.
+    ///
+    /// Simulated time only passes while the program waits or yields, so waits finish instantly.
+    #[clap(long, conflicts_with = "speed")]
+    unthrottled: bool,
+    /// The VEXos version reported to the program and to upload tools, e.g. `1.1.4` or `1.1.5-b1`.
+    #[clap(long, value_name = "VERSION", default_value_t)]
+    vexos_version: VexosVersion,
+    /// The date and time on the brain's clock when the program starts, e.g. `2024-04-25T09:30:00`.
+    ///
+    /// Defaults to the current time (in UTC).
+    #[clap(long, value_name = "DATE", value_parser = parse_date)]
+    date: Option<u64>,
+    /// Milliseconds that the brain had been powered on for when the program started.
+    #[clap(long, value_name = "MS", default_value_t = 0)]
+    uptime: u64,
+    /// The value returned by `vexSystemUsbStatus`, which is nonzero when a computer is connected.
+    #[clap(long, value_name = "STATUS", default_value_t = 1)]
+    usb_status: u32,
+    /// Warn when the program goes this many milliseconds without calling `vexTasksRun`, or 0 to
+    /// never warn.
+    ///
+    /// On a real brain, a program that doesn't yield stops the controllers and serial from updating.
+    #[clap(long, value_name = "MS", default_value_t = 1000)]
+    watchdog_timeout: u64,
+    /// Stop programs that trip the watchdog instead of just warning about them.
+    #[clap(long)]
+    watchdog_kill: bool,
+    /// Warn when the program is estimated to keep a real brain's CPU busy for at least this
+    /// percentage of a 10ms system tick.
+    ///
+    /// CPU usage is only estimated with `--unthrottled`, where simulated time passes as the program
+    /// runs instead of at the speed of the host.
+    #[clap(long, value_name = "PERCENT", default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    cpu_budget: u32,
+    /// Don't estimate how much CPU time the program would use on a real brain.
+    ///
+    /// Counting the instructions the program runs slows down the simulation, so this makes
+    /// `--unthrottled` run faster, at the cost of waits being the only thing that takes time.
+    #[clap(long)]
+    no_cpu_model: bool,
+}
+
+impl Args {
+    /// Whether to estimate the program's CPU usage, which only makes sense when simulated time
+    /// isn't tied to the host's speed.
+    fn cpu_model(&self) -> bool {
+        self.unthrottled && !self.no_cpu_model
+    }
+}
+
+/// Parses a positive speed multiplier.
+fn parse_speed(speed: &str) -> Result<f64, String> {
+    match speed.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        Ok(_) => Err(format!("speed must be a positive number, got `{speed}`")),
+        Err(err) => Err(format!("invalid speed `{speed}`: {err}")),
+    }
+}
+
+/// Parses a `START..END` range of milliseconds.
+fn parse_millis_range(range: &str) -> Result<Range<Duration>, String> {
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| format!("expected `START..END`, got `{range}`"))?;
+    let parse = |millis: &str| {
+        millis
+            .trim()
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|err| format!("invalid number of milliseconds `{millis}`: {err}"))
+    };
+    Ok(parse(start)?..parse(end)?)
 }
 
 // const PROGRAM_TYPE_USER: u32 = 0;
@@ -126,16 +278,14 @@ fn parse_code_sig(program: &[u8], protocol: &mut Protocol) -> anyhow::Result<Pro
     Ok(cold_header)
 }
 
-/// Loads a user program from a file, parsing the cold header and creating a module.
+/// Loads a user program, parsing the cold header and creating a module.
 fn load_program(
     engine: &Engine,
-    path: &Path,
+    program: &[u8],
     protocol: &mut Protocol,
     args: &Args,
 ) -> Result<(Module, ProgramOptions)> {
-    let program = fs::read(path)?;
-
-    let cold_header = parse_code_sig(&program, protocol);
+    let cold_header = parse_code_sig(program, protocol);
 
     let cold_header = if args.relaxed_code_sig {
         cold_header.unwrap_or_else(|err| {
@@ -155,44 +305,265 @@ fn load_program(
     };
 
     // this operation will do a lot of JIT compilation so it's probably the slowest part of the program
-    let module = Module::from_binary(engine, &program)?;
+    let module = Module::from_binary(engine, program)?;
     Ok((module, cold_header))
 }
 
-fn start(args: Args, sdl_request_channel: mpsc::Sender<SdlRequest>) -> Result<()> {
-    let mut protocol = Protocol::open();
-    protocol.handshake(args.imply_start)?;
+/// How often the program is interrupted to run the system task and check the watchdog.
+const EPOCH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs the simulator, returning why the last program exited.
+fn start(args: Args, sdl_request_channel: mpsc::Sender<SdlRequest>) -> Result<ExitReason> {
+    let imply_start = args.imply_start || args.terminal;
+    let mut protocol = Protocol::open(if args.terminal {
+        ProtocolMode::Terminal
+    } else {
+        ProtocolMode::Jsonl
+    });
+    protocol.handshake(imply_start)?;
+    if args.pty {
+        #[cfg(unix)]
+        {
+            protocol.attach_pty(pty::SerialPty::open()?)?;
+            protocol.open_system_port(args.vexos_version)?;
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("`--pty` is only supported on Unix");
+    }
 
-    protocol.info("Compiling...")?;
     let engine = Engine::new(
         Config::new()
             .debug_info(true)
-            .wasm_backtrace_details(WasmBacktraceDetails::Enable),
+            .wasm_backtrace_details(WasmBacktraceDetails::Enable)
+            .epoch_interruption(true)
+            .consume_fuel(args.cpu_model()),
     )?;
-    let (module, cold_header) = load_program(&engine, &args.program, &mut protocol, &args)
-        .context("Failed to load robot program")?;
-
-    protocol.info("Booting...")?;
-
-    let state = SdkState::new(module.clone(), cold_header, protocol, sdl_request_channel);
-
-    let mut store = Store::new(&engine, state);
+    // Interrupts the program every epoch so that the system task and the watchdog keep running.
+    thread::spawn({
+        let engine = engine.clone();
+        move || loop {
+            thread::sleep(EPOCH_INTERVAL);
+            engine.increment_epoch();
+        }
+    });
+    let mut program = fs::read(&args.program).context("Failed to read robot program")?;
+    let mut start_execution = imply_start;
+    let mut reason = ExitReason::Returned;
+    // Whether the program came from the system port instead of the command line.
+    let mut uploaded = false;
+    loop {
+        protocol.info("Compiling...")?;
+        let loaded = load_program(&engine, &program, &mut protocol, &args)
+            .context("Failed to load robot program");
+        let ran = match loaded {
+            Ok((module, cold_header)) => {
+                let ran;
+                (protocol, ran) = run_program(
+                    &engine,
+                    module,
+                    cold_header,
+                    protocol,
+                    &args,
+                    start_execution,
+                    sdl_request_channel.clone(),
+                )?;
+                ran
+            }
+            Err(err) => Err(err),
+        };
+        let next_program = match ran {
+            Ok((exit_reason, next_program)) => {
+                reason = exit_reason;
+                next_program
+            }
+            // A bad upload shouldn't take down the simulator, so wait for another one instead.
+            Err(err) if uploaded => {
+                protocol.error(format!("{err:?}"))?;
+                None
+            }
+            Err(err) => return Err(err),
+        };
+
+        // Like a real brain sitting on its home screen, wait for a tool to run another program.
+        let Some(next_program) = next_program.or_else(|| protocol.wait_for_program()) else {
+            return Ok(reason);
+        };
+        program = next_program;
+        uploaded = true;
+        // Programs run through the system port start right away, like they do on a real brain.
+        start_execution = true;
+    }
+}
 
-    // Here we get the metadata of the imported indirect function table.
+/// Checks that a module has the imports and exports the simulator needs to run it, returning the
+/// type of the function table it imports.
+fn program_interface(module: &Module) -> Result<TableType> {
     // User programs will request a varying starting number of entries.
     // If the starting number of entries actually given to the program is too low, it will not start successfully.
-    let imported_table_ty = module
+    let table_ty = module
         .imports()
-        .filter_map(|i| match i.ty() {
+        .find_map(|import| match import.ty() {
             ExternType::Table(table_ty) => Some(table_ty),
             _ => None,
         })
-        .next()
-        .unwrap();
+        .context("The program doesn't import a function table")?;
+    if !matches!(module.get_export("memory"), Some(ExternType::Memory(_))) {
+        anyhow::bail!("The program doesn't export its memory");
+    }
+    if !matches!(module.get_export("_entry"), Some(ExternType::Func(_))) {
+        anyhow::bail!("The program doesn't export an `_entry` function");
+    }
+    Ok(table_ty)
+}
+
+/// Builds the settings for the simulated SDK from the command line.
+fn sdk_options(args: &Args) -> Result<SdkOptions> {
+    Ok(SdkOptions {
+        auto_controllers: args.auto_controllers,
+        controller_link: LinkModel {
+            kind: args.controller_link,
+            update_interval: args.controller_interval.map(Duration::from_millis),
+            latency: Duration::from_millis(args.controller_latency),
+            dropouts: args.controller_dropout.clone(),
+        },
+        input_recorder: args
+            .record_inputs
+            .as_deref()
+            .map(InputRecorder::create)
+            .transpose()
+            .context("Failed to create the input recording")?,
+        input_playback: args
+            .replay_inputs
+            .as_deref()
+            .map(InputPlayback::open)
+            .transpose()
+            .context("Failed to open the input recording")?,
+        usd_faults: SdCardFaults {
+            capacity: args.usd_capacity,
+            read_only: args.usd_read_only,
+        },
+        usd_eject_at: args.usd_eject_at.map(Duration::from_millis),
+        serial_link: SerialLink {
+            bandwidth: args.serial_bandwidth,
+            overflow: args.serial_overflow,
+        },
+        clock_mode: if args.unthrottled {
+            ClockMode::Stepped
+        } else if args.speed == 1.0 {
+            ClockMode::RealTime
+        } else {
+            ClockMode::Scaled(args.speed)
+        },
+        cpu_budget: args.cpu_model().then(|| args.cpu_budget as f64 / 100.0),
+        system: SystemOptions {
+            version: args.vexos_version,
+            start_date: args.date,
+            uptime_at_start: Duration::from_millis(args.uptime),
+            usb_status: args.usb_status,
+        },
+    })
+}
+
+/// Runs a program until it exits, returning the protocol so that it can be reused, along with why
+/// the program exited and the program that was requested to run next, if any.
+///
+/// If the program fails before it starts running, the error is returned with the protocol so that
+/// the simulator can keep going.
+fn run_program(
+    engine: &Engine,
+    module: Module,
+    cold_header: ProgramOptions,
+    mut protocol: Protocol,
+    args: &Args,
+    start_execution: bool,
+    sdl_request_channel: mpsc::Sender<SdlRequest>,
+) -> Result<(Protocol, Result<(ExitReason, Option<Vec<u8>>)>)> {
+    if let Err(err) = protocol.info("Booting...") {
+        return Ok((protocol, Err(err)));
+    }
+    let options = match sdk_options(args) {
+        Ok(options) => options,
+        Err(err) => return Ok((protocol, Err(err))),
+    };
+    let state = SdkState::new(
+        module.clone(),
+        cold_header,
+        protocol,
+        sdl_request_channel,
+        options,
+    );
+
+    let mut store = Store::new(engine, state);
+    store.limiter(|sdk| sdk.limiter());
+    store.set_epoch_deadline(1);
+    let watchdog_timeout =
+        (args.watchdog_timeout != 0).then(|| Duration::from_millis(args.watchdog_timeout));
+    let watchdog_kill = args.watchdog_kill;
+    store.epoch_deadline_callback(move |mut ctx| {
+        // Fuel can only be read when the CPU model is enabled.
+        if let Ok(fuel) = ctx.get_fuel() {
+            ctx.data_mut().consume_fuel(fuel);
+        }
+        check_memory_limit(&mut ctx)?;
+        ctx.data_mut().run_system_tick()?;
+        let Some(timeout) = watchdog_timeout else {
+            return Ok(UpdateDeadline::Continue(1));
+        };
+        if let Some(elapsed) = ctx.data_mut().check_watchdog(timeout) {
+            let backtrace = WasmBacktrace::capture(&ctx);
+            ctx.data_mut().warn(format!(
+                "The program hasn't called vexTasksRun in {}ms, so the controllers and serial aren't updating",
+                elapsed.as_millis()
+            ))?;
+            ctx.data_mut().warn(backtrace.to_string())?;
+            if watchdog_kill {
+                return Err(ProgramExit(ExitReason::Unresponsive).into());
+            }
+        }
+        Ok(UpdateDeadline::Continue(1))
+    });
+
+    let run = match boot_program(&mut store, engine, &module, args, start_execution) {
+        Ok(run) => run,
+        Err(err) => return Ok((store.into_data().into_protocol(), Err(err))),
+    };
+
+    // We should be ready to actually run the entrypoint now.
+    store.data_mut().trace("Calling _entry()")?;
+    let reason = match run.call(&mut store, ()) {
+        Ok(()) => ExitReason::Returned,
+        Err(err) => {
+            if let Some(ProgramExit(reason)) = err.downcast_ref::<ProgramExit>() {
+                *reason
+            } else if let Some(crash) = Crash::classify(&err) {
+                store.data_mut().report_crash(crash)?;
+                ExitReason::Trapped
+            } else {
+                return Err(err.context("Call to _entry() failed"));
+            }
+        }
+    };
+    let (protocol, next_program) = store.into_data().finish(reason)?;
+    Ok((protocol, Ok((reason, next_program))))
+}
+
+/// Instantiates a program and sets up the simulator to run it, returning its entrypoint.
+fn boot_program(
+    store: &mut Store<SdkState>,
+    engine: &Engine,
+    module: &Module,
+    args: &Args,
+    start_execution: bool,
+) -> Result<TypedFunc<(), ()>> {
+    let imported_table_ty = program_interface(module)?;
+    if args.cpu_model() {
+        store.set_fuel(INITIAL_FUEL)?;
+    }
+    store.data_mut().insert_usd(args.usd.clone())?;
 
-    let mut linker = Linker::new(&engine);
-    let table = Table::new(&mut store, imported_table_ty, Ref::Func(None))?;
-    linker.define(&store, "env", "__indirect_function_table", table)?;
+    let mut linker = Linker::new(engine);
+    let table = Table::new(&mut *store, imported_table_ty, Ref::Func(None))?;
+    linker.define(&*store, "env", "__indirect_function_table", table)?;
     linker.func_wrap(
         "env",
         "sim_log_backtrace",
@@ -204,34 +575,60 @@ fn start(args: Args, sdl_request_channel: mpsc::Sender<SdlRequest>) -> Result<()
     )?;
 
     wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |sdk| sdk.wasi())?;
+    link_wasi_stdio(&mut linker, store)?;
 
     // Load and compile our module
 
-    let instance = linker.instantiate(&mut store, &module)?;
+    let instance = linker.instantiate(&mut *store, module)?;
 
     // Allocate space for the jump table. 0x700 total pages covers the entire range of the jump table.
-    let memory = instance.get_memory(&mut store, "memory").unwrap();
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .context("The program doesn't export its memory")?;
     let target_pages = 0x700;
-    let memory_size = memory.size(&store);
-    memory.grow(&mut store, target_pages - memory_size)?;
+    let memory_size = memory.size(&*store);
+    memory.grow(&mut *store, target_pages.saturating_sub(memory_size))?;
 
     // Add the jump table to memory and create the WASM FFI interface.
-    let jump_table = JumpTable::new(&mut store, memory);
-    jump_table.expose(&mut store, &table, &memory)?;
+    let jump_table = JumpTable::new(store, memory, table);
+    jump_table.expose(store, &table, &memory)?;
 
-    let run = instance.get_typed_func::<(), ()>(&mut store, "_entry")?;
-    if args.imply_start {
+    let run = instance.get_typed_func::<(), ()>(&mut *store, "_entry")?;
+    if start_execution {
         store.data_mut().execute_command(Command::StartExecution)?;
     }
     store
         .data_mut()
         .setup()
         .context("Failed to setup the program for execution")?;
-    // We should be ready to actually run the entrypoint now.
-    store.data_mut().trace("Calling _entry()")?;
-    run.call(&mut store, ())
-        .context("Call to _entry() failed")?;
-    Ok(())
+    Ok(run)
+}
+
+/// Reads the current state of an SDL gamepad in the layout of a V5 controller.
+fn controller_state(sdl_controller: &GameController) -> ControllerState {
+    ControllerState {
+        axis1: (sdl_controller.axis(Axis::LeftX) as i32) * 127 / (i16::MAX as i32),
+        axis2: -(sdl_controller.axis(Axis::LeftY) as i32) * 127 / (i16::MAX as i32),
+        axis3: -(sdl_controller.axis(Axis::RightY) as i32) * 127 / (i16::MAX as i32),
+        axis4: (sdl_controller.axis(Axis::RightX) as i32) * 127 / (i16::MAX as i32),
+        button_l1: sdl_controller.button(Button::LeftShoulder),
+        button_l2: sdl_controller.axis(Axis::TriggerLeft) > 0,
+        button_r1: sdl_controller.button(Button::RightShoulder),
+        button_r2: sdl_controller.axis(Axis::TriggerRight) > 0,
+        button_up: sdl_controller.button(Button::DPadUp),
+        button_down: sdl_controller.button(Button::DPadDown),
+        button_left: sdl_controller.button(Button::DPadLeft),
+        button_right: sdl_controller.button(Button::DPadRight),
+        button_x: sdl_controller.button(Button::X),
+        button_b: sdl_controller.button(Button::B),
+        button_y: sdl_controller.button(Button::Y),
+        button_a: sdl_controller.button(Button::A),
+        battery_capacity: 0,
+        battery_level: 0,
+        button_all: false,
+        button_sel: false,
+        flags: 0,
+    }
 }
 
 fn main() -> Result<()> {
@@ -253,14 +650,31 @@ fn main() -> Result<()> {
     let joystick_subsystem = sdl.joystick().unwrap();
     let controller_subsystem = sdl.game_controller().unwrap();
 
-    let handle = thread::spawn(move || {
-        start(args, tx).unwrap();
-    });
+    let handle = thread::spawn(move || start(args, tx).unwrap());
+
+    // Gamepads in the order SDL reported them as connected, used for automatic controller assignment.
+    let mut hotplugged: Vec<GameController> = Vec::new();
 
     while let Ok(req) = rx.recv() {
         match req {
             SdlRequest::EventPump => {
-                event_pump.pump_events();
+                for event in event_pump.poll_iter() {
+                    match event {
+                        SdlEvent::ControllerDeviceAdded { which, .. } => {
+                            let Ok(sdl_controller) = controller_subsystem.open(which) else {
+                                continue;
+                            };
+                            let id = sdl_controller.instance_id();
+                            if !hotplugged.iter().any(|c| c.instance_id() == id) {
+                                hotplugged.push(sdl_controller);
+                            }
+                        }
+                        SdlEvent::ControllerDeviceRemoved { which, .. } => {
+                            hotplugged.retain(|c| c.instance_id() != which);
+                        }
+                        _ => {}
+                    }
+                }
             }
             SdlRequest::V5Controller { guid, response } => {
                 let val = || {
@@ -279,33 +693,7 @@ fn main() -> Result<()> {
                                 continue;
                             };
 
-                            return anyhow::Ok(Some(ControllerState {
-                                axis1: (sdl_controller.axis(Axis::LeftX) as i32) * 127
-                                    / (i16::MAX as i32),
-                                axis2: -(sdl_controller.axis(Axis::LeftY) as i32) * 127
-                                    / (i16::MAX as i32),
-                                axis3: -(sdl_controller.axis(Axis::RightY) as i32) * 127
-                                    / (i16::MAX as i32),
-                                axis4: (sdl_controller.axis(Axis::RightX) as i32) * 127
-                                    / (i16::MAX as i32),
-                                button_l1: sdl_controller.button(Button::LeftShoulder),
-                                button_l2: sdl_controller.axis(Axis::TriggerLeft) > 0,
-                                button_r1: sdl_controller.button(Button::RightShoulder),
-                                button_r2: sdl_controller.axis(Axis::TriggerRight) > 0,
-                                button_up: sdl_controller.button(Button::DPadUp),
-                                button_down: sdl_controller.button(Button::DPadDown),
-                                button_left: sdl_controller.button(Button::DPadLeft),
-                                button_right: sdl_controller.button(Button::DPadRight),
-                                button_x: sdl_controller.button(Button::X),
-                                button_b: sdl_controller.button(Button::B),
-                                button_y: sdl_controller.button(Button::Y),
-                                button_a: sdl_controller.button(Button::A),
-                                battery_capacity: 0,
-                                battery_level: 0,
-                                button_all: false,
-                                button_sel: false,
-                                flags: 0,
-                            }));
+                            return anyhow::Ok(Some(controller_state(&sdl_controller)));
                         }
                     }
                     Ok(None)
@@ -313,10 +701,101 @@ fn main() -> Result<()> {
 
                 _ = response.send(val());
             }
+            SdlRequest::HotplugControllers { response } => {
+                let mut states = [None, None];
+                for (state, sdl_controller) in states
+                    .iter_mut()
+                    .zip(hotplugged.iter().filter(|c| c.attached()))
+                {
+                    *state = Some(controller_state(sdl_controller));
+                }
+                _ = response.send(states);
+            }
+        }
+    }
+
+    let reason = handle.join().unwrap();
+    std::process::exit(reason.exit_code());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends a module section, whose contents must be shorter than 128 bytes.
+    fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+        module.extend_from_slice(&[id, contents.len() as u8]);
+        module.extend_from_slice(contents);
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = vec![name.len() as u8];
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    /// Builds a program with an empty `_entry` function, which optionally imports a function
+    /// table and exports a memory.
+    fn program(imports_table: bool, exports_memory: bool) -> Module {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // A function type with no parameters or results.
+        section(&mut module, 1, &[1, 0x60, 0, 0]);
+        if imports_table {
+            let mut import = vec![1];
+            import.extend(name("env"));
+            import.extend(name("__indirect_function_table"));
+            // A `funcref` table with no minimum size.
+            import.extend_from_slice(&[0x01, 0x70, 0, 0]);
+            section(&mut module, 2, &import);
         }
+        section(&mut module, 3, &[1, 0]);
+        if exports_memory {
+            section(&mut module, 5, &[1, 0, 1]);
+        }
+        let mut exports = vec![1 + exports_memory as u8];
+        exports.extend(name("_entry"));
+        exports.extend_from_slice(&[0x00, 0]);
+        if exports_memory {
+            exports.extend(name("memory"));
+            exports.extend_from_slice(&[0x02, 0]);
+        }
+        section(&mut module, 7, &exports);
+        section(&mut module, 10, &[1, 2, 0, 0x0B]);
+        Module::from_binary(&Engine::default(), &module).unwrap()
+    }
+
+    #[test]
+    fn accepts_programs_with_a_table_and_memory() {
+        let table_ty = program_interface(&program(true, true)).unwrap();
+        assert_eq!(table_ty.minimum(), 0);
+    }
+
+    #[test]
+    fn rejects_uploads_without_a_memory_export() {
+        let err = program_interface(&program(true, false)).unwrap_err();
+        assert_eq!(err.to_string(), "The program doesn't export its memory");
     }
 
-    handle.join().unwrap();
+    #[test]
+    fn rejects_programs_without_a_function_table() {
+        let err = program_interface(&program(false, true)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The program doesn't import a function table"
+        );
+    }
 
-    Ok(())
+    #[test]
+    fn help_lists_every_exit_code() {
+        for reason in [
+            ExitReason::Returned,
+            ExitReason::Trapped,
+            ExitReason::Requested,
+            ExitReason::Stopped,
+            ExitReason::Unresponsive,
+        ] {
+            let code = format!("\n  {}  ", reason.exit_code());
+            assert!(EXIT_CODES_HELP.contains(&code), "{reason:?}");
+        }
+    }
 }
@@ -1,12 +1,26 @@
 use std::{
     collections::VecDeque,
-    io::{stdin, stdout, Stdout},
-    sync::mpsc::{self, TryRecvError},
+    io::{stderr, stdin, stdout, IsTerminal, Read, Stdout, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, TryRecvError},
+        Arc,
+    },
 };
 
 use jsonl::ReadError;
+use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, ResultExt, Snafu};
-use vexide_simulator_protocol::{Command, Event, LogLevel};
+use vexide_simulator_protocol::{
+    Command, Event, LogLevel, SerialData, TextMetrics, V5FontSize, V5Text,
+};
+
+use crate::sdk::{CrashKind, ExitReason};
+#[cfg(unix)]
+use crate::{
+    pty::{SerialPty, PTY_CHANNEL},
+    sdk::VexosVersion,
+};
 
 #[derive(Debug, Snafu)]
 pub enum ProtocolError {
@@ -27,44 +41,304 @@ pub enum ProtocolError {
         expected: i32,
         got: i32,
     },
+    #[snafu(display("Failed to write serial data to the terminal"))]
+    Terminal {
+        source: std::io::Error,
+    },
+    #[snafu(display("Invalid serial data: {message}"))]
+    InvalidSerialData {
+        message: String,
+    },
 }
 
 pub type Result<T, E = ProtocolError> = std::result::Result<T, E>;
 
+/// Protocol extension that lets the frontend pause the simulation and step it forward.
+pub const LOCKSTEP_EXTENSION: &str = "lockstep";
+
+/// Protocol extension that tells the frontend when and why the program exits.
+pub const EXIT_EXTENSION: &str = "exit";
+
+/// Protocol extension that reports how busy the program keeps the brain's CPU.
+pub const CPU_EXTENSION: &str = "cpu";
+
+/// Protocol extension that describes why the program crashed.
+pub const CRASH_EXTENSION: &str = "crash";
+
+/// The protocol extensions this simulator supports.
+const SUPPORTED_EXTENSIONS: &[&str] = &[
+    LOCKSTEP_EXTENSION,
+    EXIT_EXTENSION,
+    CPU_EXTENSION,
+    CRASH_EXTENSION,
+];
+
+/// How far to advance the simulation in a [`ExtCommand::Step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StepAmount {
+    /// Advance the simulation clock by some number of milliseconds.
+    Millis(u64),
+    /// Let the program call `vexTasksRun` some number of times.
+    Ticks(u32),
+}
+
+/// A command added by a protocol extension.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtCommand {
+    /// Block the program the next time it yields, stopping the simulation clock while it's blocked.
+    Pause,
+    /// Let the simulation run freely again.
+    Resume,
+    /// Run the simulation for a fixed amount of time or ticks, then pause it again.
+    Step(StepAmount),
+}
+
+/// An event added by a protocol extension.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtEvent {
+    /// A [`ExtCommand::Step`] has finished and the simulation is paused.
+    StepFinished {
+        /// Microseconds of simulated time since the program started.
+        time: u64,
+    },
+    /// The program has stopped running.
+    Exited {
+        reason: ExitReason,
+        /// The exit code the simulator will use if it exits because of this.
+        code: i32,
+    },
+    /// The program's estimated CPU utilization over the last system tick.
+    CpuUsage {
+        /// Microseconds of simulated time since the program started.
+        time: u64,
+        /// What percentage of the tick the CPU was busy running the program.
+        percent: u32,
+    },
+    /// The program crashed. This is sent before [`ExtEvent::Exited`].
+    Crashed {
+        kind: CrashKind,
+        /// The error message from the WebAssembly runtime.
+        message: String,
+        /// A suggestion for what might have gone wrong.
+        hint: Option<String>,
+        backtrace: Option<String>,
+    },
+}
+
+/// A message received from the frontend.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Inbound {
+    Command(Command),
+    Extension(ExtCommand),
+}
+
+impl From<Command> for Inbound {
+    fn from(command: Command) -> Self {
+        Inbound::Command(command)
+    }
+}
+
+pub type InboundSender = mpsc::Sender<Result<Inbound, jsonl::ReadError>>;
+
+/// A request from an upload tool connected to the system port.
+#[derive(Debug)]
+pub enum ProgramRequest {
+    /// Stop the current program and run the given one instead.
+    Run(Vec<u8>),
+    /// Stop the current program.
+    Stop,
+}
+
+/// How the simulator talks to whoever is running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolMode {
+    /// Exchange JSONL messages with a frontend over stdin and stdout.
+    #[default]
+    Jsonl,
+    /// Act like a serial terminal, without a frontend.
+    ///
+    /// Serial output is printed to stdout, stdin is sent to the program over serial, and logs are
+    /// printed to stderr. Everything else the frontend would normally handle is either ignored or
+    /// answered by the simulator itself.
+    Terminal,
+}
+
 pub struct Protocol {
+    mode: ProtocolMode,
     handshake_finished: bool,
     outbound: Stdout,
-    pub inbound: mpsc::Receiver<Result<Command, jsonl::ReadError>>,
+    pub inbound: mpsc::Receiver<Result<Inbound, jsonl::ReadError>>,
+    /// Used to answer requests that would normally be handled by the frontend.
+    loopback: InboundSender,
     command_process_queue: VecDeque<Command>,
+    extension_queue: VecDeque<ExtCommand>,
+    /// The extensions agreed on during the handshake.
+    extensions: Vec<String>,
+    /// A pseudo-terminal that serial data is mirrored to.
+    #[cfg(unix)]
+    pty: Option<SerialPty>,
+    /// Requests from tools connected to the system port, if it's open.
+    program_requests: Option<mpsc::Receiver<ProgramRequest>>,
+    /// Set once the terminal's input has ended and all of it has been received.
+    input_closed: Arc<AtomicBool>,
 }
 
 impl Protocol {
-    pub fn open() -> Self {
+    pub fn open(mode: ProtocolMode) -> Self {
         let stdout = stdout();
         let (tx, rx) = mpsc::channel();
-        std::thread::spawn(move || loop {
-            let stdin_lock = stdin().lock();
-            let msg = match jsonl::read(stdin_lock) {
-                Ok(msg) => Ok(msg),
-                Err(ReadError::Eof) => std::process::exit(0),
-                Err(err) => Err(err),
-            };
-
-            if tx.send(msg).is_err() {
-                break;
+        let loopback = tx.clone();
+        let input_closed = Arc::new(AtomicBool::new(false));
+        match mode {
+            ProtocolMode::Jsonl => {
+                std::thread::spawn(move || loop {
+                    let stdin_lock = stdin().lock();
+                    let msg = match jsonl::read(stdin_lock) {
+                        Ok(msg) => Ok(msg),
+                        Err(ReadError::Eof) => std::process::exit(0),
+                        Err(err) => Err(err),
+                    };
+
+                    if tx.send(msg).is_err() {
+                        break;
+                    }
+                });
             }
-        });
+            ProtocolMode::Terminal => {
+                let input_closed = input_closed.clone();
+                std::thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    loop {
+                        // Running out of input is fine, the program just won't get any more of it.
+                        let len = match stdin().read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(len) => len,
+                        };
+                        let cmd = Command::Serial(SerialData::new(1, &buf[..len]));
+                        if tx.send(Ok(cmd.into())).is_err() {
+                            break;
+                        }
+                    }
+                    input_closed.store(true, Ordering::Release);
+                });
+            }
+        }
 
         Self {
+            mode,
             handshake_finished: false,
             outbound: stdout,
             inbound: rx,
+            loopback,
             command_process_queue: VecDeque::new(),
+            extension_queue: VecDeque::new(),
+            extensions: Vec::new(),
+            #[cfg(unix)]
+            pty: None,
+            program_requests: None,
+            input_closed,
+        }
+    }
+
+    /// Connects a PTY to the program's serial port, in addition to the frontend.
+    #[cfg(unix)]
+    pub fn attach_pty(&mut self, pty: SerialPty) -> anyhow::Result<()> {
+        pty.forward_input(self.loopback.clone())?;
+        let path = pty.path().display().to_string();
+        self.pty = Some(pty);
+        self.info(format!("Serial port available at {path}"))?;
+        Ok(())
+    }
+
+    /// Opens a PTY that upload tools can use to upload and run programs.
+    #[cfg(unix)]
+    pub fn open_system_port(&mut self, version: VexosVersion) -> anyhow::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let path = crate::pty::open_system_port(tx, version)?;
+        self.program_requests = Some(rx);
+        self.info(format!("System port available at {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Returns the next request from the system port, if one has been received.
+    pub fn try_program_request(&mut self) -> Option<ProgramRequest> {
+        self.program_requests.as_ref()?.try_recv().ok()
+    }
+
+    /// Blocks until a tool asks for a program to be run.
+    ///
+    /// Returns `None` if the system port isn't open, since no request could ever arrive.
+    pub fn wait_for_program(&mut self) -> Option<Vec<u8>> {
+        let requests = self.program_requests.as_ref()?;
+        loop {
+            match requests.recv().ok()? {
+                ProgramRequest::Run(program) => return Some(program),
+                ProgramRequest::Stop => {}
+            }
         }
     }
 
+    /// Returns whether the terminal's input has ended.
+    ///
+    /// Once this is true, all of the input has already been sent as commands.
+    pub fn input_closed(&self) -> bool {
+        self.input_closed.load(Ordering::Acquire)
+    }
+
+    pub fn mode(&self) -> ProtocolMode {
+        self.mode
+    }
+
     pub fn send(&mut self, event: &Event) -> Result<()> {
-        Ok(jsonl::write(&mut self.outbound, event)?)
+        #[cfg(unix)]
+        if let (Some(pty), Event::Serial(data)) = (&mut self.pty, event) {
+            if data.channel == PTY_CHANNEL {
+                pty.write(&serial_bytes(data)?).context(TerminalSnafu)?;
+            }
+        }
+
+        match self.mode {
+            ProtocolMode::Jsonl => Ok(jsonl::write(&mut self.outbound, event)?),
+            ProtocolMode::Terminal => self.send_to_terminal(event),
+        }
+    }
+
+    fn send_to_terminal(&mut self, event: &Event) -> Result<()> {
+        match event {
+            Event::Serial(data) => {
+                let bytes = serial_bytes(data)?;
+                self.outbound.write_all(&bytes).context(TerminalSnafu)?;
+                self.outbound.flush().context(TerminalSnafu)?;
+            }
+            Event::Log { level, message } => {
+                let (color, name) = match level {
+                    LogLevel::Trace => ("2", "trace"),
+                    LogLevel::Info => ("36", "info"),
+                    LogLevel::Warn => ("33", "warn"),
+                    LogLevel::Error => ("31", "error"),
+                };
+                let mut stderr = stderr();
+                // Keep escape codes out of logs that are redirected to a file.
+                if stderr.is_terminal() {
+                    writeln!(stderr, "\x1b[{color}m{name}\x1b[0m: {message}")
+                } else {
+                    writeln!(stderr, "{name}: {message}")
+                }
+                .context(TerminalSnafu)?;
+            }
+            Event::TextMetricsRequest { text } => {
+                let cmd = Command::SetTextMetrics {
+                    text: text.clone(),
+                    metrics: estimate_text_metrics(text),
+                };
+                // The receiving end is owned by this struct, so this can't fail.
+                _ = self.loopback.send(Ok(cmd.into()));
+            }
+            // There's no screen to draw to.
+            _ => {}
+        }
+        Ok(())
     }
 
     pub fn try_next(&mut self) -> Result<Option<Command>> {
@@ -76,16 +350,26 @@ impl Protocol {
     }
 
     pub fn try_recv(&mut self) -> Result<Option<Command>> {
-        match self.inbound.try_recv() {
-            Ok(Ok(Command::Handshake { .. })) if self.handshake_finished => {
-                ReceivedHandshakeAttemptAfterHandshakeFinishedSnafu.fail()
+        loop {
+            match self.inbound.try_recv() {
+                Ok(msg) => match msg? {
+                    Inbound::Command(cmd) => return self.check_command(cmd).map(Some),
+                    Inbound::Extension(ext) => self.extension_queue.push_back(ext),
+                },
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(_) => return RecvWorkerStoppedSnafu.fail(),
             }
-            Ok(msg) => Ok(Some(msg?)),
-            Err(TryRecvError::Empty) => Ok(None),
-            Err(_) => RecvWorkerStoppedSnafu.fail(),
         }
     }
 
+    /// Rejects commands that aren't allowed at this point in the protocol.
+    fn check_command(&self, cmd: Command) -> Result<Command> {
+        if matches!(cmd, Command::Handshake { .. }) && self.handshake_finished {
+            return ReceivedHandshakeAttemptAfterHandshakeFinishedSnafu.fail();
+        }
+        Ok(cmd)
+    }
+
     pub fn next(&mut self) -> Result<Command> {
         let cmd = self
             .command_process_queue
@@ -95,11 +379,47 @@ impl Protocol {
     }
 
     pub fn recv(&mut self) -> Result<Command> {
-        let msg = self.inbound.recv().ok().context(RecvWorkerStoppedSnafu)??;
-        if matches!(msg, Command::Handshake { .. }) && self.handshake_finished {
-            return ReceivedHandshakeAttemptAfterHandshakeFinishedSnafu.fail();
+        loop {
+            match self.inbound.recv().ok().context(RecvWorkerStoppedSnafu)?? {
+                Inbound::Command(cmd) => return self.check_command(cmd),
+                Inbound::Extension(ext) => self.extension_queue.push_back(ext),
+            }
+        }
+    }
+
+    /// Blocks until a command or extension command is available to be processed.
+    pub fn wait(&mut self) -> Result<()> {
+        if !self.command_process_queue.is_empty() || !self.extension_queue.is_empty() {
+            return Ok(());
+        }
+        match self.inbound.recv().ok().context(RecvWorkerStoppedSnafu)?? {
+            Inbound::Command(cmd) => {
+                let cmd = self.check_command(cmd)?;
+                self.command_process_queue.push_back(cmd);
+            }
+            Inbound::Extension(ext) => self.extension_queue.push_back(ext),
+        }
+        Ok(())
+    }
+
+    /// Returns the next extension command that has been received, if any.
+    ///
+    /// Extension commands are only collected while receiving regular commands, so this should
+    /// be called after all available commands have been processed.
+    pub fn next_extension(&mut self) -> Option<ExtCommand> {
+        self.extension_queue.pop_front()
+    }
+
+    /// Returns whether the frontend agreed to use the given extension during the handshake.
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|ext| ext == name)
+    }
+
+    pub fn send_extension(&mut self, event: &ExtEvent) -> Result<()> {
+        match self.mode {
+            ProtocolMode::Jsonl => Ok(jsonl::write(&mut self.outbound, event)?),
+            ProtocolMode::Terminal => Ok(()),
         }
-        Ok(msg)
     }
 
     pub fn handshake(&mut self, implied: bool) -> Result<()> {
@@ -115,7 +435,7 @@ impl Protocol {
         const COMPATIBLE_PROTOCOL_VERSION: i32 = 1;
 
         let handshake = self.next()?;
-        let (version, _) = match handshake {
+        let (version, extensions) = match handshake {
             Command::Handshake {
                 version,
                 extensions,
@@ -131,9 +451,13 @@ impl Protocol {
             .fail();
         }
 
+        self.extensions = extensions
+            .into_iter()
+            .filter(|ext| SUPPORTED_EXTENSIONS.contains(&ext.as_str()))
+            .collect();
         self.send(&Event::Handshake {
             version: COMPATIBLE_PROTOCOL_VERSION,
-            extensions: vec![],
+            extensions: self.extensions.clone(),
         })?;
 
         self.handshake_finished = true;
@@ -157,6 +481,27 @@ impl Protocol {
     }
 }
 
+fn serial_bytes(data: &SerialData) -> Result<Vec<u8>> {
+    data.to_bytes()
+        .map_err(|err| ProtocolError::InvalidSerialData {
+            message: err.to_string(),
+        })
+}
+
+/// Guesses how big some text would be in the V5's monospace font, for when there's no frontend
+/// to measure it.
+fn estimate_text_metrics(text: &V5Text) -> TextMetrics {
+    let (char_width, height) = match text.font_size {
+        V5FontSize::Small => (7, 13),
+        V5FontSize::Large => (16, 32),
+        _ => (9, 17),
+    };
+    TextMetrics {
+        width: text.data.chars().count() * char_width,
+        height,
+    }
+}
+
 pub trait Log {
     fn log(&mut self, level: LogLevel, message: String) -> Result<()>;
     fn trace(&mut self, message: impl Into<String>) -> Result<()> {
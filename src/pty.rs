@@ -0,0 +1,124 @@
+//! Pseudo-terminals that stand in for the brain's USB serial ports.
+//!
+//! Tools that talk to a real brain over serial (terminals, dashboards, `screen`) can open the
+//! user port's path instead and see the program's serial output, while upload tools can use the
+//! system port.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    path::PathBuf,
+    sync::mpsc,
+};
+
+use anyhow::Context;
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    pty::openpty,
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg},
+    unistd::ttyname,
+};
+use vexide_simulator_protocol::{Command, SerialData};
+
+use crate::{
+    cdc2::SystemPort,
+    protocol::{InboundSender, ProgramRequest},
+    sdk::VexosVersion,
+};
+
+/// The serial channel that is connected to the PTY.
+pub const PTY_CHANNEL: u32 = 1;
+
+pub struct SerialPty {
+    master: File,
+    /// Kept open so that reading from the master doesn't fail while no tool is attached.
+    _slave: OwnedFd,
+    path: PathBuf,
+}
+
+/// Creates a new PTY in raw mode, like the serial ports of a real brain.
+fn open_raw() -> anyhow::Result<(File, OwnedFd, PathBuf)> {
+    let pty = openpty(None, None).context("Failed to create a PTY")?;
+
+    let mut termios = tcgetattr(&pty.slave)?;
+    cfmakeraw(&mut termios);
+    tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)?;
+
+    let path = ttyname(&pty.slave).context("Failed to get the path of the PTY")?;
+    Ok((File::from(pty.master), pty.slave, path))
+}
+
+/// Creates a PTY that acts like the brain's system port, which tools use to upload and run programs.
+///
+/// The port is served on a background thread, which sends the programs that tools ask to run
+/// through `requests`. Returns the path of the PTY.
+pub fn open_system_port(
+    requests: mpsc::Sender<ProgramRequest>,
+    version: VexosVersion,
+) -> anyhow::Result<PathBuf> {
+    let (master, slave, path) = open_raw()?;
+    std::thread::spawn(move || {
+        // Kept open so that reading from the master doesn't fail while no tool is attached.
+        let _slave = slave;
+        _ = SystemPort::new(requests, version).serve(master);
+    });
+    Ok(path)
+}
+
+impl SerialPty {
+    /// Creates a PTY that acts like the brain's user port.
+    pub fn open() -> anyhow::Result<Self> {
+        let (master, slave, path) = open_raw()?;
+
+        // Output is dropped instead of blocking the program when nothing is reading the PTY.
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+        Ok(Self {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+
+    /// The path that tools can open to connect to the simulated serial port.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Starts a thread that sends everything written to the PTY to the program as serial input.
+    pub fn forward_input(&self, commands: InboundSender) -> anyhow::Result<()> {
+        let mut master = self.master.try_clone()?;
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            loop {
+                let mut fds = [PollFd::new(master.as_fd(), PollFlags::POLLIN)];
+                if poll(&mut fds, PollTimeout::NONE).is_err() {
+                    break;
+                }
+                let len = match master.read(&mut buf) {
+                    Ok(0) => continue,
+                    Ok(len) => len,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(_) => break,
+                };
+                let cmd = Command::Serial(SerialData::new(PTY_CHANNEL, &buf[..len]));
+                if commands.send(Ok(cmd.into())).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Writes serial output to the PTY.
+    ///
+    /// Output that doesn't fit in the PTY's buffer is dropped, since it means nothing is reading it.
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.master.write_all(bytes) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
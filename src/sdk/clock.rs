@@ -0,0 +1,142 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How the simulation clock moves relative to the host's clock.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ClockMode {
+    /// Simulated time passes at the same rate as real time.
+    #[default]
+    RealTime,
+    /// Simulated time doesn't pass on its own, only when the clock is advanced.
+    Paused,
+    /// Simulated time passes at some multiple of real time.
+    Scaled(f64),
+    /// Simulated time only passes when the program waits or yields, and waiting takes no real
+    /// time at all.
+    Stepped,
+}
+
+impl ClockMode {
+    /// How many simulated seconds pass for every real second.
+    fn rate(self) -> f64 {
+        match self {
+            ClockMode::RealTime => 1.0,
+            ClockMode::Paused | ClockMode::Stepped => 0.0,
+            ClockMode::Scaled(rate) => rate,
+        }
+    }
+}
+
+/// How much simulated time passes each time the program yields in [`ClockMode::Stepped`].
+const TICK_QUANTUM: Duration = Duration::from_millis(1);
+
+/// How much simulated time passes each time the program reads the clock in [`ClockMode::Stepped`].
+///
+/// This keeps programs that busy-wait on the clock without yielding from spinning forever.
+const READ_QUANTUM: Duration = Duration::from_micros(10);
+
+#[derive(Debug)]
+struct ClockState {
+    mode: ClockMode,
+    /// The simulated time when the mode was last changed.
+    base_time: Duration,
+    /// The real time when the mode was last changed.
+    base_instant: Instant,
+}
+
+impl ClockState {
+    fn now(&self) -> Duration {
+        let real_elapsed = self.base_instant.elapsed().as_secs_f64();
+        self.base_time + Duration::from_secs_f64(real_elapsed * self.mode.rate())
+    }
+}
+
+/// The simulation's clock, which every time source in the SDK reads from.
+///
+/// Time starts at zero when the program starts. Cloning the clock gives another handle to the
+/// same clock.
+#[derive(Debug, Clone)]
+pub struct SimClock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+impl SimClock {
+    pub fn new(mode: ClockMode) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ClockState {
+                mode,
+                base_time: Duration::ZERO,
+                base_instant: Instant::now(),
+            })),
+        }
+    }
+
+    /// Returns how much simulated time has passed since the program started.
+    pub fn now(&self) -> Duration {
+        self.state.lock().unwrap().now()
+    }
+
+    /// Returns the current time for the program to see.
+    ///
+    /// Unlike [`SimClock::now`], this moves stepped clocks forward slightly, since the program
+    /// reading the clock takes time on a real brain too.
+    pub fn read(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = state.now();
+        if state.mode == ClockMode::Stepped {
+            state.base_time += READ_QUANTUM;
+        }
+        now
+    }
+
+    fn mode(&self) -> ClockMode {
+        self.state.lock().unwrap().mode
+    }
+
+    pub fn set_mode(&self, mode: ClockMode) {
+        let mut state = self.state.lock().unwrap();
+        state.base_time = state.now();
+        state.base_instant = Instant::now();
+        state.mode = mode;
+    }
+
+    /// Moves simulated time forward, on top of any time that passes on its own.
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().base_time += duration;
+    }
+
+    /// Called every time the program yields to the SDK.
+    pub fn tick(&self) {
+        if self.mode() == ClockMode::Stepped {
+            self.advance(TICK_QUANTUM);
+        }
+    }
+
+    /// Called when the program has spent some amount of CPU time running.
+    ///
+    /// Real time already passed while it ran, so this only matters for stepped clocks.
+    pub fn spend(&self, cpu_time: Duration) {
+        if self.mode() == ClockMode::Stepped {
+            self.advance(cpu_time);
+        }
+    }
+
+    /// Blocks the current thread until some amount of simulated time has passed.
+    ///
+    /// While the clock is paused, this waits for a short amount of real time instead, so that
+    /// callers polling the clock don't spin.
+    pub fn sleep(&self, duration: Duration) {
+        let mode = self.mode();
+        let rate = mode.rate();
+        if mode == ClockMode::Stepped {
+            self.advance(duration);
+        } else if rate > 0.0 {
+            thread::sleep(Duration::from_secs_f64(duration.as_secs_f64() / rate));
+        } else {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
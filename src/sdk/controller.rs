@@ -1,4 +1,4 @@
-use std::sync::mpsc;
+use std::{collections::VecDeque, ops::Range, sync::mpsc, time::Duration};
 
 use anyhow::{anyhow, Context};
 use sdl2::joystick::Guid;
@@ -7,7 +7,11 @@ use wasmtime::*;
 
 use crate::sdk::SdkState;
 
-use super::JumpTableBuilder;
+use super::{
+    clock::SimClock,
+    recording::{InputPlayback, InputRecorder},
+    JumpTableBuilder,
+};
 
 // MARK: Constants
 
@@ -94,13 +98,12 @@ pub fn build_controller_jump_table(memory: Memory, builder: &mut JumpTableBuilde
         move |mut caller: Caller<'_, SdkState>, id: u32, index: u32| -> Result<i32> {
             let index = V5_ControllerIndex(index);
 
-            let controller = caller
+            let state = caller
                 .data_mut()
                 .inputs
-                .controller(id, false)
+                .observed_state(id)
                 .context("Invalid controller id")?;
-            if let Some(controller) = controller {
-                let states = controller.current_state;
+            if let Some(states) = state {
                 match index {
                     V5_ControllerIndex::AnaLeftX => Ok(states.axis1),
                     V5_ControllerIndex::AnaLeftY => Ok(states.axis2),
@@ -135,16 +138,134 @@ pub fn build_controller_jump_table(memory: Memory, builder: &mut JumpTableBuilde
     builder.insert(
         0x1a8,
         move |mut caller: Caller<'_, SdkState>, id: u32| -> Result<i32> {
-            caller.data_mut().inputs.connected(id).map(|c| c as i32)
+            caller.data_mut().inputs.status(id).map(|s| s.0 as i32)
         },
     );
 }
 
+// MARK: Link model
+
+/// The kind of connection between a controller and the brain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LinkKind {
+    /// Wireless connection through VEXnet radios.
+    #[default]
+    Vexnet,
+    /// Wired connection through a smart cable.
+    Tethered,
+}
+
+impl LinkKind {
+    /// The typical rate at which controller updates reach the brain over this kind of link.
+    pub const fn default_update_interval(self) -> Duration {
+        match self {
+            LinkKind::Vexnet => Duration::from_millis(20),
+            LinkKind::Tethered => Duration::from_millis(5),
+        }
+    }
+
+    const fn status(self) -> constants::V5_ControllerStatus {
+        match self {
+            LinkKind::Vexnet => constants::V5_ControllerStatus::kV5ControllerVexnet,
+            LinkKind::Tethered => constants::V5_ControllerStatus::kV5ControllerTethered,
+        }
+    }
+}
+
+/// Emulation settings for the link between the controllers and the brain.
+#[derive(Debug, Clone, Default)]
+pub struct LinkModel {
+    pub kind: LinkKind,
+    /// How often a new controller state is sent to the brain.
+    ///
+    /// Defaults to the typical rate for the kind of link.
+    pub update_interval: Option<Duration>,
+    /// How long it takes for an update to arrive at the brain after it was sent.
+    pub latency: Duration,
+    /// Time spans, measured from the start of the program, during which the link is down.
+    pub dropouts: Vec<Range<Duration>>,
+}
+
+impl LinkModel {
+    fn update_interval(&self) -> Duration {
+        self.update_interval
+            .unwrap_or_else(|| self.kind.default_update_interval())
+    }
+
+    /// Returns whether the link is down at the given time.
+    pub fn dropped_out(&self, now: Duration) -> bool {
+        self.dropouts.iter().any(|dropout| dropout.contains(&now))
+    }
+}
+
+/// Controller updates travelling between a controller and the brain.
+#[derive(Default)]
+struct LinkState {
+    /// When the last update was sent by the controller.
+    last_sent: Option<Duration>,
+    /// Updates that have been sent, along with the time they will arrive at the brain.
+    in_flight: VecDeque<(Duration, ControllerState)>,
+    /// The latest update that has arrived at the brain.
+    received: Option<ControllerState>,
+}
+
+impl LinkState {
+    /// Sends the controller's state if an update is due and receives any updates that have arrived.
+    fn poll(&mut self, model: &LinkModel, now: Duration, state: ControllerState) {
+        if model.dropped_out(now) {
+            // Everything in flight is lost, and the brain forgets the controller until it reconnects.
+            *self = LinkState::default();
+            return;
+        }
+
+        let interval = model.update_interval();
+        let sent_at = match self.last_sent {
+            None => Some(now),
+            Some(last) if interval.is_zero() => Some(now).filter(|now| *now > last),
+            Some(last) if now >= last + interval => {
+                // Updates are sent on a fixed cadence, no matter how often the program polls.
+                let periods = (now - last).as_nanos() / interval.as_nanos();
+                Some(last + interval * periods as u32)
+            }
+            Some(_) => None,
+        };
+        if let Some(sent_at) = sent_at {
+            self.last_sent = Some(sent_at);
+            self.in_flight.push_back((sent_at + model.latency, state));
+        }
+
+        while let Some((arrival, _)) = self.in_flight.front() {
+            if *arrival > now {
+                break;
+            }
+            self.received = self.in_flight.pop_front().map(|(_, state)| state);
+        }
+    }
+}
+
 // MARK: API
 
 pub struct V5Controller {
+    /// The latest state reported by the controller itself.
     pub current_state: ControllerState,
     pub sdl_guid: Option<Guid>,
+    link: LinkState,
+}
+
+impl V5Controller {
+    fn new(current_state: ControllerState, sdl_guid: Option<Guid>) -> Self {
+        Self {
+            current_state,
+            sdl_guid,
+            link: LinkState::default(),
+        }
+    }
+
+    /// Returns the state of the controller as seen by the brain, or `None` if no update has
+    /// arrived over the link yet.
+    pub fn received_state(&self) -> Option<ControllerState> {
+        self.link.received
+    }
 }
 
 pub enum SdlRequest {
@@ -152,22 +273,70 @@ pub enum SdlRequest {
         guid: Guid,
         response: oneshot::Sender<Result<Option<ControllerState>>>,
     },
+    /// Requests the states of the first two gamepads in the order they were connected.
+    HotplugControllers {
+        response: oneshot::Sender<[Option<ControllerState>; 2]>,
+    },
     EventPump,
 }
 
 pub struct Inputs {
     controllers: [Option<V5Controller>; 2],
     request_channel: mpsc::Sender<SdlRequest>,
+    /// Whether controllers are assigned by SDL hotplug order rather than by the frontend.
+    auto_assign: bool,
+    link: LinkModel,
+    recorder: Option<InputRecorder>,
+    /// Recorded controller states that are replayed instead of the real controllers.
+    playback: Option<InputPlayback>,
+    clock: SimClock,
 }
 
 impl Inputs {
-    pub fn new(request_channel: mpsc::Sender<SdlRequest>) -> Self {
+    pub fn new(
+        request_channel: mpsc::Sender<SdlRequest>,
+        auto_assign: bool,
+        link: LinkModel,
+        recorder: Option<InputRecorder>,
+        playback: Option<InputPlayback>,
+        clock: SimClock,
+    ) -> Self {
         Inputs {
             controllers: Default::default(),
             request_channel,
+            auto_assign,
+            link,
+            recorder,
+            playback,
+            clock,
         }
     }
 
+    /// Returns whether controllers are assigned automatically by SDL hotplug order.
+    pub fn auto_assigned(&self) -> bool {
+        self.auto_assign
+    }
+
+    /// Assigns the first two connected gamepads to the primary and partner controllers,
+    /// disconnecting any controller slot without a gamepad.
+    fn assign_hotplugged(&mut self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.request_channel
+            .send(SdlRequest::HotplugControllers { response: tx })
+            .ok();
+        let states = rx
+            .recv()
+            .map_err(|_| anyhow!("Controller request failed: main thread is not listening"))?;
+
+        for (controller, state) in self.controllers.iter_mut().zip(states) {
+            match (controller.as_mut(), state) {
+                (Some(controller), Some(state)) => controller.current_state = state,
+                (_, state) => *controller = state.map(|state| V5Controller::new(state, None)),
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_controller(
         &mut self,
         id: u32,
@@ -180,14 +349,11 @@ impl Inputs {
 
         match update {
             Some(update) => {
-                let controller = match update {
-                    ControllerUpdate::Raw(state) => V5Controller {
-                        current_state: state,
-                        sdl_guid: None,
-                    },
-                    ControllerUpdate::UUID(uuid) => V5Controller {
+                let (current_state, sdl_guid) = match update {
+                    ControllerUpdate::Raw(state) => (state, None),
+                    ControllerUpdate::UUID(uuid) => (
                         // TODO: use Default::default()
-                        current_state: ControllerState {
+                        ControllerState {
                             axis1: 0,
                             axis2: 0,
                             axis3: 0,
@@ -210,10 +376,20 @@ impl Inputs {
                             flags: 0,
                             battery_capacity: 0,
                         },
-                        sdl_guid: Some(Guid::from_string(&uuid)?),
-                    },
+                        Some(Guid::from_string(&uuid)?),
+                    ),
                 };
-                self.controllers[id as usize] = Some(controller);
+                // Keep the link state around so updates already in flight aren't lost.
+                match self.controllers[id as usize].as_mut() {
+                    Some(controller) => {
+                        controller.current_state = current_state;
+                        controller.sdl_guid = sdl_guid;
+                    }
+                    None => {
+                        self.controllers[id as usize] =
+                            Some(V5Controller::new(current_state, sdl_guid));
+                    }
+                }
             }
             None => {
                 self.controllers[id as usize] = None;
@@ -223,26 +399,63 @@ impl Inputs {
         Ok(())
     }
 
-    /// Returns whether the controller with the given id is connected.
+    /// Returns how the controller with the given id is connected to the brain.
     ///
     /// Fails if the id is invalid.
-    pub fn connected(&mut self, id: u32) -> Result<bool> {
-        Ok(self.controller(id, true)?.is_some())
+    pub fn status(&mut self, id: u32) -> Result<constants::V5_ControllerStatus> {
+        let now = self.clock.now();
+        let dropped_out = self.link.dropped_out(now);
+        let kind = self.link.kind;
+        let connected = match self.playback.as_mut() {
+            Some(playback) => playback.state(id, now)?.is_some(),
+            None => self.controller(id, true)?.is_some(),
+        };
+        Ok(if connected && !dropped_out {
+            kind.status()
+        } else {
+            constants::V5_ControllerStatus::kV5ControllerOffline
+        })
+    }
+
+    /// Returns the state of the given controller as the program sees it.
+    ///
+    /// The state is replayed from a recording if one was provided, and is written to the
+    /// input recording if one is being made.
+    pub fn observed_state(&mut self, id: u32) -> Result<Option<ControllerState>> {
+        let now = self.clock.now();
+        let state = match self.playback.as_mut() {
+            Some(playback) => playback.state(id, now)?,
+            None => self
+                .controller(id, false)?
+                .and_then(|controller| controller.received_state()),
+        };
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(now, id, state)?;
+        }
+        Ok(state)
     }
 
     /// Get the last known state for the given controller.
     ///
     /// If `lazy` is false, the function will communicate with the main thread to get new states for
-    /// controllers managed by SDL2.
+    /// controllers managed by SDL2. Either way, the latest state is sent over the emulated link.
     pub fn controller(&mut self, id: u32, lazy: bool) -> Result<Option<&mut V5Controller>> {
         if id >= self.controllers.len() as u32 {
             anyhow::bail!("Invalid controller id");
         }
 
+        if self.auto_assign && !lazy {
+            self.assign_hotplugged()?;
+        }
+
+        let now = self.clock.now();
         let Some(controller) = self.controllers[id as usize].as_mut() else {
             return Ok(None);
         };
         if lazy {
+            controller
+                .link
+                .poll(&self.link, now, controller.current_state);
             return Ok(Some(controller));
         }
         if let Some(guid) = controller.sdl_guid {
@@ -259,11 +472,12 @@ impl Inputs {
             if let Some(res) = res {
                 controller.current_state = res;
             }
-            Ok(Some(controller))
-        } else {
-            // The frontend didn't provide a controller ID for updating it so we're just left with a constant controller state.
-            Ok(Some(controller))
         }
+        // If the frontend didn't provide a controller ID for updating it we're just left with a constant controller state.
+        controller
+            .link
+            .poll(&self.link, now, controller.current_state);
+        Ok(Some(controller))
     }
 
     /// Get new events from the SDL event pump and update the SDK's representation of the controller states.
@@ -272,6 +486,10 @@ impl Inputs {
             .send(SdlRequest::EventPump)
             .map_err(|_| anyhow!("Event pump request failed: main thread is not listening"))?;
 
+        if self.auto_assign {
+            self.assign_hotplugged()?;
+        }
+
         for index in 0..self.controllers.len() {
             self.controller(index as u32, true)?;
         }
@@ -279,3 +497,114 @@ impl Inputs {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// A neutral controller state, tagged with `axis1` so updates can be told apart.
+    fn state(axis1: i32) -> ControllerState {
+        ControllerState {
+            axis1,
+            axis2: 0,
+            axis3: 0,
+            axis4: 0,
+            button_l1: false,
+            button_l2: false,
+            button_r1: false,
+            button_r2: false,
+            button_up: false,
+            button_down: false,
+            button_left: false,
+            button_right: false,
+            button_x: false,
+            button_b: false,
+            button_y: false,
+            button_a: false,
+            button_sel: false,
+            battery_level: 0,
+            button_all: false,
+            flags: 0,
+            battery_capacity: 0,
+        }
+    }
+
+    fn received(link: &LinkState) -> Option<i32> {
+        link.received.map(|state| state.axis1)
+    }
+
+    #[test]
+    fn sends_on_a_fixed_cadence() {
+        let model = LinkModel::default();
+        let mut link = LinkState::default();
+
+        link.poll(&model, Duration::ZERO, state(1));
+        assert_eq!(received(&link), Some(1));
+        // The next update isn't due until 20ms.
+        link.poll(&model, 10 * MS, state(2));
+        assert_eq!(received(&link), Some(1));
+        link.poll(&model, 25 * MS, state(3));
+        assert_eq!(received(&link), Some(3));
+        // Polling late doesn't shift the cadence: the last update was sent at 20ms, not 25ms.
+        link.poll(&model, 40 * MS, state(4));
+        assert_eq!(received(&link), Some(4));
+        assert_eq!(link.last_sent, Some(40 * MS));
+    }
+
+    #[test]
+    fn delays_updates_by_the_latency() {
+        let model = LinkModel {
+            latency: 30 * MS,
+            ..Default::default()
+        };
+        let mut link = LinkState::default();
+
+        link.poll(&model, Duration::ZERO, state(1));
+        link.poll(&model, 20 * MS, state(2));
+        assert_eq!(received(&link), None);
+        link.poll(&model, 30 * MS, state(3));
+        assert_eq!(received(&link), Some(1));
+        link.poll(&model, 50 * MS, state(4));
+        assert_eq!(received(&link), Some(2));
+    }
+
+    #[test]
+    fn sends_every_poll_without_an_interval() {
+        let model = LinkModel {
+            update_interval: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let mut link = LinkState::default();
+
+        link.poll(&model, Duration::ZERO, state(1));
+        link.poll(&model, Duration::ZERO, state(2));
+        assert_eq!(received(&link), Some(1));
+        assert_eq!(link.in_flight.len(), 0);
+        link.poll(&model, MS, state(3));
+        assert_eq!(received(&link), Some(3));
+    }
+
+    #[test]
+    fn forgets_the_controller_during_dropouts() {
+        let model = LinkModel {
+            latency: 5 * MS,
+            dropouts: vec![10 * MS..40 * MS],
+            ..Default::default()
+        };
+        let mut link = LinkState::default();
+
+        link.poll(&model, Duration::ZERO, state(1));
+        link.poll(&model, 5 * MS, state(2));
+        assert_eq!(received(&link), Some(1));
+        link.poll(&model, 10 * MS, state(3));
+        assert_eq!(received(&link), None);
+        assert!(link.in_flight.is_empty());
+        // The controller reconnects and sends right away.
+        link.poll(&model, 40 * MS, state(4));
+        assert_eq!(received(&link), None);
+        link.poll(&model, 45 * MS, state(5));
+        assert_eq!(received(&link), Some(4));
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+/// The clock speed of the brain's Cortex-A9 user processor, in Hz.
+const CPU_FREQUENCY: f64 = 666_666_667.0;
+
+/// Roughly how many Cortex-A9 cycles one unit of wasmtime fuel (about one WebAssembly
+/// instruction) takes once compiled to ARM.
+const CYCLES_PER_FUEL: f64 = 2.0;
+
+/// The fuel the program is given when it starts, which is more than it can ever use up.
+pub const INITIAL_FUEL: u64 = u64::MAX;
+
+/// Estimates how much time the program would spend running on a real brain's CPU.
+#[derive(Debug)]
+pub struct CpuModel {
+    /// The program's remaining fuel the last time it was checked.
+    last_fuel: u64,
+    /// Estimated CPU time the program has used since the window started.
+    busy: Duration,
+    /// The simulated time when the current utilization window started.
+    window_start: Duration,
+    /// Whether the program was already reported as going over its CPU budget.
+    overloaded: bool,
+}
+
+impl CpuModel {
+    pub fn new() -> Self {
+        Self {
+            last_fuel: INITIAL_FUEL,
+            busy: Duration::ZERO,
+            window_start: Duration::ZERO,
+            overloaded: false,
+        }
+    }
+
+    /// Records the fuel the program has left, returning how long a brain would have taken to run
+    /// the instructions it has used since the last call.
+    pub fn consume(&mut self, remaining_fuel: u64) -> Duration {
+        let used = self.last_fuel.saturating_sub(remaining_fuel);
+        self.last_fuel = remaining_fuel;
+        let cpu_time = Duration::from_secs_f64(used as f64 * CYCLES_PER_FUEL / CPU_FREQUENCY);
+        self.busy += cpu_time;
+        cpu_time
+    }
+
+    /// Returns the fraction of simulated time the CPU was busy since the last call, then starts a
+    /// new window at `now`.
+    ///
+    /// This is measured against simulated time, which the program's CPU time is part of, so a
+    /// value of `1.0` means the program never gave the CPU a break.
+    pub fn take_utilization(&mut self, now: Duration) -> f64 {
+        let elapsed = now.saturating_sub(self.window_start);
+        let utilization = if elapsed.is_zero() {
+            0.0
+        } else {
+            self.busy.as_secs_f64() / elapsed.as_secs_f64()
+        };
+        self.busy = Duration::ZERO;
+        self.window_start = now;
+        utilization
+    }
+
+    /// Updates whether the program has used up its budget, returning `true` if it just did.
+    pub fn check_budget(&mut self, utilization: f64, budget: f64) -> bool {
+        let was_overloaded = self.overloaded;
+        self.overloaded = utilization >= budget;
+        self.overloaded && !was_overloaded
+    }
+}
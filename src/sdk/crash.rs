@@ -0,0 +1,84 @@
+//! Explanations for the ways a program can crash.
+
+use serde::{Deserialize, Serialize};
+use wasmtime::{Trap, WasmBacktrace};
+
+/// What caused the program to crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrashKind {
+    StackOverflow,
+    MemoryOutOfBounds,
+    Unreachable,
+    IndirectCallToNull,
+    BadSignature,
+    TableOutOfBounds,
+    IntegerOverflow,
+    IntegerDivisionByZero,
+    BadConversionToInteger,
+    Other,
+}
+
+impl CrashKind {
+    /// Returns a suggestion for what might have gone wrong, if there is one.
+    pub fn hint(self) -> Option<&'static str> {
+        Some(match self {
+            CrashKind::StackOverflow => {
+                "The program ran out of stack space. Look for infinite recursion or very large local variables."
+            }
+            CrashKind::MemoryOutOfBounds => {
+                "The program accessed memory outside of the V5 user region, which usually means it used a null or dangling pointer."
+            }
+            CrashKind::Unreachable => {
+                "The program panicked or aborted, which also happens when it runs out of memory. Any panic message should have been printed before this."
+            }
+            CrashKind::IndirectCallToNull => {
+                "The program called an SDK function that the simulator doesn't implement yet, or a null function pointer."
+            }
+            CrashKind::BadSignature => {
+                "The program called an SDK function with the wrong signature. It may have been built for a different version of the SDK."
+            }
+            CrashKind::TableOutOfBounds => "The program called an invalid function pointer.",
+            CrashKind::IntegerOverflow => "An integer operation overflowed.",
+            CrashKind::IntegerDivisionByZero => "The program divided an integer by zero.",
+            CrashKind::BadConversionToInteger => {
+                "The program converted a float that was NaN or out of range to an integer."
+            }
+            CrashKind::Other => return None,
+        })
+    }
+}
+
+/// A description of why the program crashed.
+#[derive(Debug)]
+pub struct Crash {
+    pub kind: CrashKind,
+    /// The error message from wasmtime.
+    pub message: String,
+    pub backtrace: Option<String>,
+}
+
+impl Crash {
+    /// Describes an error that stopped the program, if it was a crash.
+    pub fn classify(err: &anyhow::Error) -> Option<Self> {
+        let kind = match err.downcast_ref::<Trap>()? {
+            Trap::StackOverflow => CrashKind::StackOverflow,
+            Trap::MemoryOutOfBounds | Trap::HeapMisaligned => CrashKind::MemoryOutOfBounds,
+            Trap::UnreachableCodeReached => CrashKind::Unreachable,
+            Trap::IndirectCallToNull => CrashKind::IndirectCallToNull,
+            Trap::BadSignature => CrashKind::BadSignature,
+            Trap::TableOutOfBounds => CrashKind::TableOutOfBounds,
+            Trap::IntegerOverflow => CrashKind::IntegerOverflow,
+            Trap::IntegerDivisionByZero => CrashKind::IntegerDivisionByZero,
+            Trap::BadConversionToInteger => CrashKind::BadConversionToInteger,
+            _ => CrashKind::Other,
+        };
+
+        Some(Self {
+            kind,
+            message: err.root_cause().to_string(),
+            backtrace: err
+                .downcast_ref::<WasmBacktrace>()
+                .map(|backtrace| backtrace.to_string()),
+        })
+    }
+}
@@ -1,10 +1,4 @@
-use std::{
-    io::Cursor,
-    mem::size_of,
-    num::NonZeroU16,
-    thread::sleep,
-    time::{Duration, Instant},
-};
+use std::{io::Cursor, mem::size_of, num::NonZeroU16, time::Duration};
 
 use anyhow::{bail, Context};
 use base64::prelude::*;
@@ -33,7 +27,7 @@ use crate::{
     ProgramOptions,
 };
 
-use super::{clone_c_string, JumpTableBuilder, MemoryExt, SdkState};
+use super::{clock::SimClock, clone_c_string, JumpTableBuilder, MemoryExt, SdkState};
 
 // MARK: Jump Table
 
@@ -359,11 +353,11 @@ pub fn build_display_jump_table(memory: Memory, builder: &mut JumpTableBuilder)
         0x7a0,
         move |mut caller: Caller<'_, SdkState>, vsync_wait: i32, run_scheduler: i32| {
             caller.data_mut().display_ctx().render()?;
-            let vsync_finish = Instant::now() + Duration::from_secs_f64(1.0 / 60.0);
+            let sdk = caller.data_mut();
+            let vsync_finish = sdk.clock.now() + Duration::from_secs_f64(1.0 / 60.0);
             if vsync_wait != 0 {
-                let sdk = caller.data_mut();
-                while Instant::now() < vsync_finish {
-                    sleep(Duration::from_millis(1));
+                while sdk.clock.now() < vsync_finish {
+                    sdk.clock.sleep(Duration::from_millis(1));
                     if run_scheduler != 0 {
                         sdk.recv_all_commands()?;
                     }
@@ -848,33 +842,57 @@ impl<'a> DisplayCtx<'a> {
     }
 
     /// Draws the blue program header at the top of the display.
-    fn draw_header(&mut self) -> anyhow::Result<()> {
-        self.with_colors(HEADER_BG, RGB8::default(), |ctx| {
+    ///
+    /// The header is outside of the program's clip region, so it is drawn with a clip region covering the
+    /// whole display.
+    pub fn draw_header(&mut self) -> anyhow::Result<()> {
+        let clip_region = self.display.clip_region;
+        let last_font_size = self.display.last_font_size;
+        self.display.clip_region = Rect {
+            top_left: [0, 0].into(),
+            bottom_right: [DISPLAY_WIDTH, DISPLAY_HEIGHT].into(),
+        };
+
+        let result = self.with_colors(WHITE, HEADER_BG, |ctx| {
             ctx.draw(
                 Shape::Rectangle {
                     top_left: [0, 0].into(),
                     bottom_right: [DISPLAY_WIDTH, HEADER_HEIGHT].into(),
                 },
                 false,
-                false,
-            )
-        })?;
+                true,
+            )?;
 
-        let elapsed = self.display.start_instant.elapsed().as_secs();
-        let secs = elapsed % 60;
-        let mins = elapsed / 60;
-        let time = format!("{:01}:{:02}", mins, secs);
-        self.write(
-            V5Text {
-                data: time,
-                font_family: V5FontFamily::TimerMono,
-                font_size: V5FontSize::Large,
-            },
-            TextLocation::Coordinates {
-                point: [DISPLAY_WIDTH / 2, 3].into(),
-            },
-            true,
-        )?;
+            let elapsed = ctx.display.clock.now().as_secs();
+            let secs = elapsed % 60;
+            let mins = elapsed / 60;
+            let time = format!("{:01}:{:02}", mins, secs);
+            ctx.write(
+                V5Text {
+                    data: time,
+                    font_family: V5FontFamily::TimerMono,
+                    font_size: V5FontSize::Large,
+                },
+                TextLocation::Coordinates {
+                    point: [DISPLAY_WIDTH / 2, 3].into(),
+                },
+                true,
+            )?;
+            ctx.display.header_secs = Some(elapsed);
+            anyhow::Ok(())
+        });
+
+        self.display.clip_region = clip_region;
+        self.display.last_font_size = last_font_size;
+        result
+    }
+
+    /// Redraws the program header if its timer is out of date.
+    pub fn update_header(&mut self) -> anyhow::Result<()> {
+        let elapsed = self.display.clock.now().as_secs();
+        if self.display.header_secs.is_some_and(|secs| secs != elapsed) {
+            self.draw_header()?;
+        }
         Ok(())
     }
 
@@ -947,24 +965,27 @@ pub struct Display {
     pub foreground_color: RGB8,
     /// The display's saved background color.
     pub background_color: RGB8,
-    start_instant: Instant,
+    clock: SimClock,
     program_options: ProgramOptions,
     /// Cache for text layout calculations, to avoid re-calculating the same text layout multiple times in a row.
     text_metrics_cache: Option<(V5Text, TextMetrics)>,
     last_font_size: V5FontSize,
+    /// The timer value shown in the program header, or `None` if the header hasn't been drawn yet.
+    header_secs: Option<u64>,
     double_buffered: bool,
     clip_region: Rect,
 }
 
 impl Display {
-    pub fn new(program_options: ProgramOptions, start_instant: Instant) -> Self {
+    pub fn new(program_options: ProgramOptions, clock: SimClock) -> Self {
         Self {
             foreground_color: program_options.default_fg_color(),
             background_color: program_options.default_bg_color(),
             program_options,
             text_metrics_cache: None,
-            start_instant,
+            clock,
             last_font_size: V5FontSize::Normal,
+            header_secs: None,
             double_buffered: false,
             clip_region: Rect {
                 top_left: Point2 {
@@ -0,0 +1,99 @@
+//! Limits that keep the program within the memory a real brain gives it.
+
+use std::fmt;
+
+use wasmtime::{Engine, ResourceLimiter};
+
+/// The address where the user program region of the brain's memory ends.
+///
+/// Addresses in the program's linear memory are the same as addresses on the brain, so its memory
+/// can't grow past this point (0x800 pages).
+const USER_REGION_END: usize = 0x0800_0000;
+
+/// The most entries the program's function table can have.
+///
+/// On the brain, the table holds pointers to functions in the program's 4 MiB slot, and ARM
+/// functions take up at least 4 bytes, so no program has more functions than this.
+const MAX_TABLE_ELEMENTS: u32 = 0x10_0000;
+
+/// A record of the program trying to use more memory than a brain has.
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Memory { desired: usize },
+    Table { desired: u32 },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory { desired } => write!(
+                f,
+                "The program tried to grow its memory to {desired:#x} bytes, but the V5 user region ends at {USER_REGION_END:#x}, so the allocation failed"
+            ),
+            Self::Table { desired } => write!(
+                f,
+                "The program tried to grow its function table to {desired} entries, but a V5 program can't have more than {MAX_TABLE_ELEMENTS}, so the table didn't grow"
+            ),
+        }
+    }
+}
+
+/// Stops the program's memory and function table from growing past what the V5 user region can
+/// hold.
+///
+/// Growing past the limit fails the same way running out of memory on a real brain does, so the
+/// program's allocator sees the failure instead of the program crashing.
+pub struct UserRegionLimiter {
+    exceeded: Option<LimitExceeded>,
+    /// Used to interrupt the program so that the failure can be reported while the code that caused
+    /// it is still on the stack.
+    engine: Engine,
+}
+
+impl UserRegionLimiter {
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            exceeded: None,
+            engine,
+        }
+    }
+
+    /// Returns the last time the limit was hit, if it has been since the last call.
+    pub fn take_exceeded(&mut self) -> Option<LimitExceeded> {
+        self.exceeded.take()
+    }
+
+    fn exceed(&mut self, exceeded: LimitExceeded) {
+        self.exceeded = Some(exceeded);
+        // The program's next epoch check calls back into the simulator, which reports it.
+        self.engine.increment_epoch();
+    }
+}
+
+impl ResourceLimiter for UserRegionLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        if desired > USER_REGION_END {
+            self.exceed(LimitExceeded::Memory { desired });
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        if desired > MAX_TABLE_ELEMENTS {
+            self.exceed(LimitExceeded::Table { desired });
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,184 @@
+//! Pausing and stepping the simulation for frontends using the lockstep extension.
+
+use std::time::Duration;
+
+use crate::protocol::StepAmount;
+
+use super::clock::{ClockMode, SimClock};
+
+/// Where the frontend has asked the simulation to pause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// The simulation is paused until the frontend steps or resumes it.
+    Paused,
+    /// Pause once the simulation clock reaches this time.
+    Time(Duration),
+    /// Pause after the program yields this many more times.
+    Ticks(u32),
+}
+
+/// Keeps track of where the frontend has asked the simulation to pause, and runs the clock to match.
+///
+/// The clock only stops while the program is blocked at a yield. SDK calls that wait on the clock,
+/// like a render waiting for vsync or a serial write waiting for the link, still finish when a
+/// pause is requested in the middle of them.
+#[derive(Debug)]
+pub struct Lockstep {
+    target: Option<Target>,
+    clock: SimClock,
+    /// The clock mode to go back to when the frontend resumes the simulation.
+    free_mode: ClockMode,
+}
+
+impl Lockstep {
+    pub fn new(clock: SimClock, free_mode: ClockMode) -> Self {
+        Self {
+            target: None,
+            clock,
+            free_mode,
+        }
+    }
+
+    /// Pauses the simulation the next time the program yields.
+    pub fn pause(&mut self) {
+        self.target = Some(Target::Paused);
+    }
+
+    pub fn resume(&mut self) {
+        self.target = None;
+        self.clock.set_mode(self.free_mode);
+    }
+
+    pub fn step(&mut self, amount: StepAmount) {
+        self.target = Some(match amount {
+            StepAmount::Millis(millis) => {
+                Target::Time(self.clock.now() + Duration::from_millis(millis))
+            }
+            StepAmount::Ticks(ticks) => Target::Ticks(ticks),
+        });
+        self.clock.set_mode(ClockMode::Stepped);
+    }
+
+    /// Counts a tick towards the current step.
+    ///
+    /// This happens before new commands are received, so that a step only counts the ticks that
+    /// happen after it was requested.
+    pub fn count_tick(&mut self) {
+        if let Some(Target::Ticks(ticks)) = &mut self.target {
+            *ticks = ticks.saturating_sub(1);
+        }
+    }
+
+    /// Called when the program yields, stopping the clock if the simulation should pause.
+    ///
+    /// Returns whether the current step has just finished.
+    pub fn on_yield(&mut self) -> bool {
+        let finished = match self.target {
+            Some(Target::Time(until)) => self.clock.now() >= until,
+            Some(Target::Ticks(ticks)) => ticks == 0,
+            _ => false,
+        };
+        if finished {
+            self.target = Some(Target::Paused);
+        }
+        if self.paused() {
+            self.clock.set_mode(ClockMode::Paused);
+        }
+        finished
+    }
+
+    /// Returns whether the simulation is paused, or will be when the program next yields.
+    pub fn paused(&self) -> bool {
+        self.target == Some(Target::Paused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Waits on the clock like a blocked SDK call does, returning whether the wait finished.
+    fn wait_in_sdk_call(clock: &SimClock, duration: Duration) -> bool {
+        let until = clock.now() + duration;
+        for _ in 0..1000 {
+            if clock.now() >= until {
+                return true;
+            }
+            clock.sleep(MS);
+        }
+        false
+    }
+
+    #[test]
+    fn pausing_during_a_blocked_write_lets_it_finish() {
+        let clock = SimClock::new(ClockMode::Stepped);
+        let mut lockstep = Lockstep::new(clock.clone(), ClockMode::Stepped);
+
+        lockstep.pause();
+        assert!(lockstep.paused());
+        // Like a serial write waiting for the link to catch up.
+        assert!(wait_in_sdk_call(&clock, 20 * MS));
+
+        // The clock only stops once the program yields.
+        assert!(!lockstep.on_yield());
+        let now = clock.now();
+        clock.sleep(MS);
+        clock.tick();
+        assert_eq!(clock.now(), now);
+
+        lockstep.resume();
+        assert!(!lockstep.paused());
+        clock.sleep(MS);
+        assert_eq!(clock.now(), now + MS);
+    }
+
+    #[test]
+    fn pausing_during_a_render_lets_it_finish() {
+        let clock = SimClock::new(ClockMode::RealTime);
+        let mut lockstep = Lockstep::new(clock.clone(), ClockMode::RealTime);
+
+        lockstep.pause();
+        // Like a render waiting for vsync.
+        assert!(wait_in_sdk_call(
+            &clock,
+            Duration::from_secs_f64(1.0 / 60.0)
+        ));
+        lockstep.on_yield();
+        let now = clock.now();
+        clock.sleep(MS);
+        assert_eq!(clock.now(), now);
+    }
+
+    #[test]
+    fn steps_a_number_of_ticks() {
+        let clock = SimClock::new(ClockMode::RealTime);
+        let mut lockstep = Lockstep::new(clock.clone(), ClockMode::RealTime);
+
+        lockstep.step(StepAmount::Ticks(2));
+        assert!(!lockstep.on_yield());
+        lockstep.count_tick();
+        assert!(!lockstep.on_yield());
+        lockstep.count_tick();
+        assert!(lockstep.on_yield());
+        assert!(lockstep.paused());
+        assert!(!lockstep.on_yield());
+    }
+
+    #[test]
+    fn steps_an_amount_of_time() {
+        let clock = SimClock::new(ClockMode::RealTime);
+        let mut lockstep = Lockstep::new(clock.clone(), ClockMode::RealTime);
+
+        lockstep.step(StepAmount::Millis(5));
+        // Stepping runs the clock only as the program uses time.
+        clock.sleep(4 * MS);
+        assert!(!lockstep.on_yield());
+        clock.sleep(MS);
+        assert!(lockstep.on_yield());
+        let now = clock.now();
+        clock.sleep(MS);
+        assert_eq!(clock.now(), now);
+    }
+}
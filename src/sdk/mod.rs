@@ -1,49 +1,184 @@
 use std::{
     collections::HashMap,
     ffi::{CStr, CString, FromBytesUntilNulError},
+    fmt,
+    path::PathBuf,
     sync::mpsc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 use component::ResourceTable;
 
-use display::DisplayCtx;
-use serial::{build_serial_jump_table, Serial};
-use vexide_simulator_protocol::{Command, CompMode, CompetitionMode, Event, LogLevel};
+use display::{DisplayCtx, HEADER_HEIGHT};
+use serial::{build_serial_jump_table, send_serial, Serial};
+use vexide_simulator_protocol::{Command, CompMode, CompetitionMode, Event, LogLevel, TouchEvent};
 use wasmtime::*;
-use wasmtime_wasi::{preview1::WasiP1Ctx, WasiCtx, WasiCtxBuilder, WasiView};
+use wasmtime_wasi::{
+    pipe::{ClosedInputStream, SinkOutputStream},
+    preview1::WasiP1Ctx,
+    DirPerms, FilePerms, WasiCtx, WasiCtxBuilder, WasiView,
+};
 
 use crate::{
-    protocol::{self, Log, Protocol},
+    protocol::{
+        self, ExtCommand, ExtEvent, Log, ProgramRequest, Protocol, CPU_EXTENSION, CRASH_EXTENSION,
+        EXIT_EXTENSION, LOCKSTEP_EXTENSION,
+    },
     ProgramOptions,
 };
 
 use self::{
+    clock::SimClock,
     controller::{build_controller_jump_table, Inputs},
+    cpu::CpuModel,
     display::{build_display_jump_table, Display},
+    limits::UserRegionLimiter,
+    lockstep::Lockstep,
+    system::{build_system_jump_table, System},
+    touch::{build_touch_jump_table, run_touch_callbacks, Touchscreen},
+    usd::{build_usd_jump_table, SdCard, USD_MOUNT_POINT},
 };
 
+mod clock;
 mod controller;
+mod cpu;
+mod crash;
 pub mod display;
+mod limits;
+mod lockstep;
+mod recording;
 mod serial;
+mod system;
+mod touch;
+mod usd;
+
+pub use clock::ClockMode;
+pub use controller::{LinkKind, LinkModel, SdlRequest};
+pub use cpu::INITIAL_FUEL;
+pub use crash::{Crash, CrashKind};
+pub use recording::{InputPlayback, InputRecorder};
+pub use serial::{link_wasi_stdio, SerialLink, SerialOverflow};
+pub use system::{parse_date, SystemOptions, VexosVersion};
+pub use usd::SdCardFaults;
+
+/// Simulator settings which are chosen when the simulator is launched.
+#[derive(Debug, Default)]
+pub struct SdkOptions {
+    /// Assign physical gamepads to the V5 controllers in the order SDL detects them, instead of
+    /// relying on the frontend to pick them.
+    pub auto_controllers: bool,
+    /// Emulation settings for the link between the controllers and the brain.
+    pub controller_link: LinkModel,
+    /// Where to record the controller states seen by the program.
+    pub input_recorder: Option<InputRecorder>,
+    /// A recording of controller states to use instead of the real controllers.
+    pub input_playback: Option<InputPlayback>,
+    /// Faults to inject into the SD card.
+    pub usd_faults: SdCardFaults,
+    /// How long after the program starts to remove the SD card.
+    pub usd_eject_at: Option<Duration>,
+    /// Emulation settings for the serial link to the frontend.
+    pub serial_link: SerialLink,
+    /// How simulated time passes relative to real time.
+    pub clock_mode: ClockMode,
+    /// The fraction of each system tick the program may keep the CPU busy before it's reported, or
+    /// `None` if the program's CPU usage isn't being estimated.
+    pub cpu_budget: Option<f64>,
+    /// Values reported by the system APIs.
+    pub system: SystemOptions,
+}
+
+/// How often the brain's system task updates devices.
+const SYSTEM_TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Why the program stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitReason {
+    /// The program returned from its entrypoint.
+    Returned,
+    /// The program called `vexSystemExitRequest`.
+    Requested,
+    /// The program was stopped from outside, e.g. by tapping the program header.
+    Stopped,
+    /// The program crashed.
+    Trapped,
+    /// The program was killed by the watchdog for not yielding.
+    Unresponsive,
+}
+
+impl ExitReason {
+    /// The simulator's process exit code for a program that exited for this reason.
+    ///
+    /// Keep this in sync with the exit codes listed in the simulator's `--help`.
+    pub const fn exit_code(self) -> i32 {
+        match self {
+            ExitReason::Returned => 0,
+            ExitReason::Trapped => 1,
+            ExitReason::Requested => 2,
+            ExitReason::Stopped => 3,
+            ExitReason::Unresponsive => 4,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Returned => write!(f, "returned from its entrypoint"),
+            ExitReason::Requested => write!(f, "requested to exit"),
+            ExitReason::Stopped => write!(f, "was stopped"),
+            ExitReason::Trapped => write!(f, "crashed"),
+            ExitReason::Unresponsive => write!(f, "was killed by the watchdog"),
+        }
+    }
+}
+
+/// Error used to unwind the program's stack when it exits before returning from its entrypoint.
+#[derive(Debug)]
+pub struct ProgramExit(pub ExitReason);
 
-pub use controller::SdlRequest;
+impl fmt::Display for ProgramExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The program {}", self.0)
+    }
+}
+
+impl std::error::Error for ProgramExit {}
 
 /// The state of the SDK, containing the program's WASM module, the robot display, and other peripherals.
 pub struct SdkState {
     module: Module,
-    program_start: Instant,
+    clock: SimClock,
+    lockstep: Lockstep,
     display: Display,
     program_options: ProgramOptions,
     inputs: Inputs,
+    touch: Touchscreen,
     competition_mode: CompetitionMode,
+    system: System,
     protocol: Protocol,
     is_executing: bool,
+    /// Whether the user has asked for the program to be stopped.
+    stop_requested: bool,
     serial: Serial,
+    usd: SdCard,
+    usd_eject_at: Option<Duration>,
     wasi: WasiP1Ctx,
+    /// A program that was uploaded through the system port to run after this one is stopped.
+    next_program: Option<Vec<u8>>,
+    /// When the system task should next update devices.
+    next_system_tick: Duration,
+    cpu: CpuModel,
+    cpu_budget: Option<f64>,
+    limiter: UserRegionLimiter,
+    /// When the program last yielded to the SDK, in real time.
+    last_yield: Instant,
+    /// Whether the watchdog has already reported that the program stopped yielding.
+    watchdog_tripped: bool,
 }
 
 impl SdkState {
@@ -52,35 +187,86 @@ impl SdkState {
         program_options: ProgramOptions,
         protocol: Protocol,
         sdl_request_channel: mpsc::Sender<SdlRequest>,
+        options: SdkOptions,
     ) -> Self {
-        let start = Instant::now();
+        let clock = SimClock::new(options.clock_mode);
+        let serial = Serial::new(options.serial_link);
+        let wasi = wasi_builder().build_p1();
+        let limiter = UserRegionLimiter::new(module.engine().clone());
         SdkState {
             module,
-            display: Display::new(program_options, start),
+            display: Display::new(program_options, clock.clone()),
             program_options,
-            inputs: Inputs::new(sdl_request_channel),
-            program_start: start,
+            inputs: Inputs::new(
+                sdl_request_channel,
+                options.auto_controllers,
+                options.controller_link,
+                options.input_recorder,
+                options.input_playback,
+                clock.clone(),
+            ),
+            touch: Touchscreen::new(),
+            clock,
+            lockstep: Lockstep::new(clock.clone(), options.clock_mode),
             competition_mode: CompetitionMode::default(),
+            system: System::new(options.system),
             protocol,
             is_executing: false,
-            serial: Serial::new(),
-            wasi: WasiCtxBuilder::new()
-                .allow_blocking_current_thread(true)
-                .allow_tcp(false)
-                .allow_udp(false)
-                .build_p1(),
+            stop_requested: false,
+            serial,
+            usd: SdCard::new(options.usd_faults),
+            usd_eject_at: options.usd_eject_at,
+            wasi,
+            next_program: None,
+            next_system_tick: Duration::ZERO,
+            cpu: CpuModel::new(),
+            cpu_budget: options.cpu_budget,
+            limiter,
+            last_yield: Instant::now(),
+            watchdog_tripped: false,
         }
     }
 
+    /// Inserts the SD card that the program starts with, if any.
+    pub fn insert_usd(&mut self, root: Option<PathBuf>) -> anyhow::Result<()> {
+        if root.is_some() {
+            self.usd
+                .set_root(root)
+                .context("Failed to insert the SD card")?;
+            self.rebuild_wasi()?;
+        }
+        Ok(())
+    }
+
     /// Signal that the simulator is ready to begin and process all setup commands.
     pub fn setup(&mut self) -> anyhow::Result<()> {
         self.protocol.send(&Event::Ready)?;
         while !self.is_executing {
             self.recv_command()?;
         }
+        self.feed_watchdog();
         Ok(())
     }
 
+    /// Records that the program has yielded to the SDK, resetting the watchdog.
+    fn feed_watchdog(&mut self) {
+        self.last_yield = Instant::now();
+        self.watchdog_tripped = false;
+    }
+
+    /// Returns how long the program has gone without yielding if it's longer than the timeout.
+    ///
+    /// This only returns a value the first time the timeout is reached, until the program yields
+    /// again.
+    pub fn check_watchdog(&mut self, timeout: Duration) -> Option<Duration> {
+        let elapsed = self.last_yield.elapsed();
+        if elapsed < timeout || self.watchdog_tripped {
+            return None;
+        }
+        self.watchdog_tripped = true;
+        Some(elapsed)
+    }
+
     /// Process the next command, blocking if it hasn't been received yet.
     pub fn recv_command(&mut self) -> anyhow::Result<()> {
         let cmd = self.protocol.next()?;
@@ -88,10 +274,24 @@ impl SdkState {
     }
 
     /// Process all available commands.
+    ///
+    /// Fails with [`ProgramExit`] if one of the commands stopped the program.
     pub fn recv_all_commands(&mut self) -> anyhow::Result<()> {
         while let Some(cmd) = self.protocol.try_next()? {
             self.execute_command(cmd)?;
         }
+        while let Some(ext) = self.protocol.next_extension() {
+            self.execute_extension(ext)?;
+        }
+        while let Some(request) = self.protocol.try_program_request() {
+            if let ProgramRequest::Run(program) = request {
+                self.next_program = Some(program);
+            }
+            self.stop_requested = true;
+        }
+        if self.stop_requested {
+            return Err(ProgramExit(ExitReason::Stopped).into());
+        }
         Ok(())
     }
 
@@ -101,12 +301,30 @@ impl SdkState {
             Command::Handshake { .. } => {
                 panic!("Cannot execute a handshake command after the simulator has started.")
             }
-            Command::Touch { pos, event } => todo!(),
+            Command::Touch { pos, event } => {
+                // Like on a real brain, tapping the program header exits the program.
+                if pos.y < HEADER_HEIGHT {
+                    if matches!(event, TouchEvent::Press) {
+                        self.stop_requested = true;
+                    }
+                } else {
+                    self.touch.touch(pos, event);
+                }
+            }
             Command::ControllerUpdate(primary, partner) => {
-                self.inputs.set_controller(0, primary)?;
-                self.inputs.set_controller(1, partner)?;
+                if self.inputs.auto_assigned() {
+                    self.warn("Ignoring controller update because controllers are being assigned automatically")?;
+                } else {
+                    self.inputs.set_controller(0, primary)?;
+                    self.inputs.set_controller(1, partner)?;
+                }
+            }
+            Command::USD { root } => {
+                if let Err(err) = self.usd.set_root(root) {
+                    self.warn(format!("{err:#} (the SD card will be removed)"))?;
+                }
+                self.update_wasi_usd()?;
             }
-            Command::USD { root } => todo!(),
             Command::VEXLinkOpened { port, mode } => todo!(),
             Command::VEXLinkClosed { port } => todo!(),
             Command::CompetitionMode(mode) => {
@@ -120,14 +338,21 @@ impl SdkState {
                 }
 
                 self.is_executing = true;
+                self.display_ctx().draw_header()?;
             }
             Command::SetBatteryCapacity { capacity } => todo!(),
             Command::SetTextMetrics { text, metrics } => {
                 self.display.set_metrics_cache(text, metrics);
             }
             Command::Serial(serial_data) => {
-                self.serial
+                let dropped = self
+                    .serial
                     .buffer_input(serial_data.channel, &serial_data.to_bytes()?)?;
+                if dropped > 0 {
+                    self.warn(format!(
+                        "Dropped {dropped} bytes of serial input because the input buffer is full"
+                    ))?;
+                }
             }
         }
         Ok(())
@@ -138,20 +363,244 @@ impl SdkState {
         self.is_executing
     }
 
+    /// Process a command added by a protocol extension.
+    fn execute_extension(&mut self, ext: ExtCommand) -> anyhow::Result<()> {
+        if !self.protocol.has_extension(LOCKSTEP_EXTENSION) {
+            self.warn(format!(
+                "Ignoring {ext:?} because the lockstep extension wasn't agreed on during the handshake"
+            ))?;
+            return Ok(());
+        }
+        match ext {
+            ExtCommand::Pause => self.lockstep.pause(),
+            ExtCommand::Resume => self.lockstep.resume(),
+            ExtCommand::Step(amount) => self.lockstep.step(amount),
+        }
+        Ok(())
+    }
+
+    /// Pauses the simulation if the current lockstep step has finished, then blocks the program
+    /// until the frontend steps or resumes it.
+    fn update_lockstep(&mut self) -> anyhow::Result<()> {
+        if self.lockstep.on_yield() {
+            self.protocol.send_extension(&ExtEvent::StepFinished {
+                time: self.clock.now().as_micros() as u64,
+            })?;
+        }
+
+        while self.lockstep.paused() {
+            self.protocol.wait()?;
+            self.recv_all_commands()?;
+        }
+        Ok(())
+    }
+
+    /// Accounts for the CPU time the program has used, given how much fuel it has left.
+    pub fn consume_fuel(&mut self, remaining_fuel: u64) {
+        let cpu_time = self.cpu.consume(remaining_fuel);
+        self.clock.spend(cpu_time);
+    }
+
+    /// Tells the user if the program has failed to allocate memory because it hit the brain's limit
+    /// and the failure hasn't been reported yet.
+    ///
+    /// Use [`check_memory_limit`] instead while the program is running, so that the report includes
+    /// a backtrace.
+    fn report_memory_limit(&mut self) -> anyhow::Result<()> {
+        if let Some(exceeded) = self.limiter.take_exceeded() {
+            self.error(exceeded.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Updates devices if it's time for the brain's system task to run.
+    ///
+    /// VEXos does this every 10ms regardless of what the program is doing, so this is called both
+    /// when the program yields and periodically while it's running.
+    pub fn run_system_tick(&mut self) -> anyhow::Result<()> {
+        let now = self.clock.now();
+        if now < self.next_system_tick {
+            return Ok(());
+        }
+        self.next_system_tick += SYSTEM_TICK_INTERVAL;
+        if self.next_system_tick <= now {
+            // Skip the ticks that were missed instead of running them all at once.
+            self.next_system_tick = now + SYSTEM_TICK_INTERVAL;
+        }
+
+        if let Some(budget) = self.cpu_budget {
+            let utilization = self.cpu.take_utilization(now);
+            if self.cpu.check_budget(utilization, budget) {
+                self.warn(format!(
+                    "The program kept the CPU {:.0}% busy since the last 10ms system tick, so it may not keep up on a real brain",
+                    utilization * 100.0
+                ))?;
+            }
+            if self.protocol.has_extension(CPU_EXTENSION) {
+                self.protocol.send_extension(&ExtEvent::CpuUsage {
+                    time: now.as_micros() as u64,
+                    percent: (utilization * 100.0).round() as u32,
+                })?;
+            }
+        }
+
+        if self.usd_eject_at.is_some_and(|eject_at| now >= eject_at) {
+            self.usd_eject_at = None;
+            self.usd.set_root(None)?;
+            self.info("The SD card was removed")?;
+            self.update_wasi_usd()?;
+        }
+        self.inputs.update()?;
+        self.serial.flush(&mut self.protocol, now)?;
+        self.display_ctx().update_header()?;
+        Ok(())
+    }
+
     pub fn run_tasks(&mut self) -> anyhow::Result<()> {
+        self.clock.tick();
+        self.lockstep.count_tick();
         self.recv_all_commands()?;
-        self.inputs.update()?;
-        self.serial.flush(&mut self.protocol)?;
+        self.update_lockstep()?;
+        self.run_system_tick()?;
+        // Time spent blocked in here (e.g. while paused) doesn't count against the program.
+        self.feed_watchdog();
+        Ok(())
+    }
+
+    /// Tells the user why the program crashed and how they might fix it.
+    pub fn report_crash(&mut self, crash: Crash) -> anyhow::Result<()> {
+        self.report_memory_limit()?;
+        let hint = crash.kind.hint();
+        let mut message = format!("The program crashed: {}", crash.message);
+        if let Some(hint) = hint {
+            message = format!("{message}\n{hint}");
+        }
+        if let Some(backtrace) = &crash.backtrace {
+            message = format!("{message}\n{backtrace}");
+        }
+        self.error(message)?;
+        if self.protocol.has_extension(CRASH_EXTENSION) {
+            self.protocol.send_extension(&ExtEvent::Crashed {
+                kind: crash.kind,
+                message: crash.message,
+                hint: hint.map(String::from),
+                backtrace: crash.backtrace,
+            })?;
+        }
         Ok(())
     }
 
+    /// Cleans up after the program has finished running, making sure no buffered output is lost,
+    /// and tells the frontend why it exited.
+    ///
+    /// Returns the protocol so that it can be reused, along with the program that was requested
+    /// to run next, if any.
+    pub fn finish(mut self, reason: ExitReason) -> anyhow::Result<(Protocol, Option<Vec<u8>>)> {
+        self.serial
+            .flush_all(&mut self.protocol, self.clock.now())?;
+        self.report_memory_limit()?;
+        self.info(format!("The program {reason}"))?;
+        if self.protocol.has_extension(EXIT_EXTENSION) {
+            self.protocol.send_extension(&ExtEvent::Exited {
+                reason,
+                code: reason.exit_code(),
+            })?;
+        }
+        Ok((self.protocol, self.next_program))
+    }
+
+    /// Gives back the protocol without finishing the program, for programs that never started.
+    pub fn into_protocol(self) -> Protocol {
+        self.protocol
+    }
+
     pub fn display_ctx(&mut self) -> DisplayCtx {
         self.display.ctx(&mut self.protocol)
     }
 
+    /// Gives WASI access to a newly inserted or removed SD card.
+    ///
+    /// Once the program is running, its WASI context is left alone, since replacing it would
+    /// invalidate every file descriptor the program has open.
+    fn update_wasi_usd(&mut self) -> anyhow::Result<()> {
+        if self.is_executing {
+            self.warn("The SD card was changed while the program was running, which WASI file APIs will not see")?;
+        } else {
+            self.rebuild_wasi()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the program's WASI context so that it has access to the SD card, if one is inserted.
+    ///
+    /// The card is preopened at the same path that the `vexFile*` APIs accept, so both views of it agree.
+    fn rebuild_wasi(&mut self) -> anyhow::Result<()> {
+        let mut builder = wasi_builder();
+        if let Some(root) = self.usd.root() {
+            let (dir_perms, file_perms) = if self.usd.faults().read_only {
+                (DirPerms::READ, FilePerms::READ)
+            } else {
+                (DirPerms::all(), FilePerms::all())
+            };
+            if let Err(err) = builder.preopened_dir(root, USD_MOUNT_POINT, dir_perms, file_perms) {
+                self.warn(format!(
+                    "Failed to give WASI access to the SD card: {err:#}"
+                ))?;
+            }
+        }
+        self.wasi = builder.build_p1();
+        Ok(())
+    }
+
+    /// Writes to a serial channel, sending the output to the frontend when the buffer fills up or
+    /// hasn't been flushed in a while.
+    ///
+    /// Output that doesn't fit in the buffer is dropped if the link is set to drop it, but still
+    /// counts as written.
+    pub fn write_serial(&mut self, channel: u32, buffer: &[u8]) -> anyhow::Result<usize> {
+        let protocol = &mut self.protocol;
+        self.serial
+            .write_through(channel, buffer, &self.clock, |channel, bytes| {
+                send_serial(protocol, channel, bytes)
+            })
+    }
+
     pub fn wasi(&mut self) -> &mut WasiP1Ctx {
         &mut self.wasi
     }
+
+    pub fn limiter(&mut self) -> &mut UserRegionLimiter {
+        &mut self.limiter
+    }
+}
+
+/// Reports a failed allocation past the brain's limit, with a backtrace of the code that tried it.
+///
+/// This is called whenever the running program calls back into the simulator, which happens right
+/// after a refused allocation because the limiter interrupts the program.
+pub fn check_memory_limit(mut store: impl AsContextMut<Data = SdkState>) -> anyhow::Result<()> {
+    let mut store = store.as_context_mut();
+    if let Some(exceeded) = store.data_mut().limiter.take_exceeded() {
+        let backtrace = WasmBacktrace::capture(&store);
+        store.data_mut().error(format!("{exceeded}\n{backtrace}"))?;
+    }
+    Ok(())
+}
+
+/// Creates a builder for the program's WASI context with the simulator's default settings.
+///
+/// The program's standard streams are connected to serial channel 1, like they are on a real brain.
+fn wasi_builder() -> WasiCtxBuilder {
+    let mut builder = WasiCtxBuilder::new();
+    builder
+        // Standard input and output go over serial through `link_wasi_stdio` instead.
+        .stdin(ClosedInputStream)
+        .stdout(SinkOutputStream)
+        .stderr(SinkOutputStream)
+        .allow_blocking_current_thread(true)
+        .allow_tcp(false)
+        .allow_udp(false);
+    builder
 }
 
 impl Log for SdkState {
@@ -194,10 +643,11 @@ pub struct JumpTable {
 }
 
 impl JumpTable {
-    /// Creates a new jump table which will use the given memory, and populates it with the default API.
+    /// Creates a new jump table which will use the given memory and indirect function table, and populates it
+    /// with the default API.
     ///
     /// No changes are actually to the user program made apart from creating the resources for the jump table.
-    pub fn new(store: &mut Store<SdkState>, memory: Memory) -> Self {
+    pub fn new(store: &mut Store<SdkState>, memory: Memory, table: Table) -> Self {
         let mut builder = JumpTableBuilder {
             store,
             jump_table: JumpTable {
@@ -208,20 +658,18 @@ impl JumpTable {
         build_display_jump_table(memory, &mut builder);
         build_controller_jump_table(memory, &mut builder);
         build_serial_jump_table(memory, &mut builder);
+        build_touch_jump_table(memory, &mut builder);
+        build_usd_jump_table(memory, &mut builder);
+        build_system_jump_table(memory, &mut builder);
 
         // vexTasksRun
         builder.insert(0x05c, move |mut caller: Caller<'_, SdkState>| {
-            caller.data_mut().run_tasks()
-        });
-
-        // vexSystemHighResTimeGet
-        builder.insert(0x134, move |caller: Caller<'_, SdkState>| -> Result<u64> {
-            Ok(caller.data().program_start.elapsed().as_micros() as u64)
-        });
-
-        // vexSystemExitRequest
-        builder.insert(0x130, move || {
-            std::process::exit(0);
+            if let Ok(fuel) = caller.get_fuel() {
+                caller.data_mut().consume_fuel(fuel);
+            }
+            check_memory_limit(&mut caller)?;
+            caller.data_mut().run_tasks()?;
+            run_touch_callbacks(&mut caller, &table)
         });
 
         bitflags! {
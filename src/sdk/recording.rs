@@ -0,0 +1,125 @@
+use std::{
+    io::{BufReader, LineWriter},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Context;
+use fs_err::File;
+use jsonl::ReadError;
+use serde::{Deserialize, Serialize};
+use vexide_simulator_protocol::ControllerState;
+
+/// A controller state observed by the program, stored as one line of a JSONL recording.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedInput {
+    /// Microseconds since the program started.
+    time: u64,
+    /// The controller the state belongs to (0 for primary, 1 for partner).
+    id: u32,
+    /// The state of the controller, or `None` if it was disconnected.
+    state: Option<ControllerState>,
+}
+
+/// Writes the controller states seen by the program to a file.
+///
+/// States are only written when they change, which is enough to replay them exactly.
+#[derive(Debug)]
+pub struct InputRecorder {
+    file: LineWriter<File>,
+    last_states: [Option<ControllerState>; 2],
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            file: LineWriter::new(File::create(path)?),
+            last_states: [None; 2],
+        })
+    }
+
+    /// Records that the program saw the given controller state.
+    pub fn record(
+        &mut self,
+        time: Duration,
+        id: u32,
+        state: Option<ControllerState>,
+    ) -> anyhow::Result<()> {
+        let last_state = self
+            .last_states
+            .get_mut(id as usize)
+            .context("Invalid controller id")?;
+        if *last_state == state {
+            return Ok(());
+        }
+        *last_state = state;
+
+        jsonl::write(
+            &mut self.file,
+            &RecordedInput {
+                time: time.as_micros() as u64,
+                id,
+                state,
+            },
+        )
+        .context("Failed to write to the input recording")?;
+        Ok(())
+    }
+}
+
+/// Replays a recording made by [`InputRecorder`] in place of the real controllers.
+#[derive(Debug)]
+pub struct InputPlayback {
+    inputs: Vec<RecordedInput>,
+    /// Index of the next input that hasn't been replayed yet.
+    cursor: usize,
+    states: [Option<ControllerState>; 2],
+}
+
+impl InputPlayback {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut inputs = Vec::new();
+        loop {
+            match jsonl::read::<_, RecordedInput>(&mut reader) {
+                Ok(input) => inputs.push(input),
+                Err(ReadError::Eof) => break,
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!(
+                            "Failed to read line {} of the input recording",
+                            inputs.len() + 1
+                        )
+                    })
+                }
+            }
+        }
+        // Recordings are written in order, but this keeps hand-edited ones working too.
+        inputs.sort_by_key(|input| input.time);
+
+        Ok(Self {
+            inputs,
+            cursor: 0,
+            states: [None; 2],
+        })
+    }
+
+    /// Returns the recorded state of the given controller at the given time.
+    pub fn state(&mut self, id: u32, time: Duration) -> anyhow::Result<Option<ControllerState>> {
+        let time = time.as_micros() as u64;
+        while let Some(input) = self.inputs.get(self.cursor) {
+            if input.time > time {
+                break;
+            }
+            if let Some(state) = self.states.get_mut(input.id as usize) {
+                *state = input.state;
+            }
+            self.cursor += 1;
+        }
+
+        self.states
+            .get(id as usize)
+            .copied()
+            .context("Invalid controller id")
+    }
+}
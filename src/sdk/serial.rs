@@ -1,6 +1,10 @@
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Range,
+    time::Duration,
+};
 
-use anyhow::{anyhow, bail, Context};
+use anyhow::{anyhow, Context};
 use vexide_simulator_protocol::{Event, SerialData};
 use wasmtime::*;
 
@@ -10,7 +14,7 @@ use crate::{
     sdk::SdkState,
 };
 
-use super::{JumpTableBuilder, MemoryExt};
+use super::{clock::SimClock, JumpTableBuilder, MemoryExt};
 
 // MARK: Jump table
 
@@ -19,7 +23,7 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
     builder.insert(
         0x898,
         move |mut caller: Caller<'_, SdkState>, channel: u32, c: u32| -> Result<i32> {
-            let written = caller.data_mut().serial.write(channel, &[c as u8]);
+            let written = caller.data_mut().write_serial(channel, &[c as u8]);
             Ok(written.map(|w| w as i32).unwrap_or(-1))
         },
     );
@@ -27,9 +31,8 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
     builder.insert(
         0x89c,
         move |mut caller: Caller<'_, SdkState>, channel: u32, data: u32, len: u32| -> Result<i32> {
-            let (memory, sdk) = memory.data_and_store_mut(&mut caller);
-            let buffer = &memory[data as usize..(data + len) as usize];
-            let written = sdk.serial.write(channel, buffer);
+            let buffer = memory.data(&caller)[data as usize..(data + len) as usize].to_vec();
+            let written = caller.data_mut().write_serial(channel, &buffer);
             Ok(written.map(|w| w as i32).unwrap_or(-1))
         },
     );
@@ -57,6 +60,18 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
             Ok(byte.unwrap_or(-1))
         },
     );
+    // vexSerialReadAvail
+    builder.insert(
+        0x8a8,
+        move |mut caller: Caller<'_, SdkState>, channel: u32| -> Result<i32> {
+            let available = caller
+                .data_mut()
+                .serial
+                .num_available_bytes(channel)
+                .map(|a| a as i32);
+            Ok(available.unwrap_or(-1))
+        },
+    );
     // vexSerialWriteFree
     // TODO: Can this return input buffer capacity?
     builder.insert(
@@ -90,8 +105,7 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
             }
             let is_err = caller
                 .data_mut()
-                .serial
-                .write_all(1, buf.as_bytes())
+                .write_serial(USER_CHANNEL, buf.as_bytes())
                 .is_err();
             if is_err {
                 return Ok(-1);
@@ -160,100 +174,616 @@ pub fn build_serial_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
 const STDOUT_BUFFER_SIZE: usize = 2048;
 const STDIN_BUFFER_SIZE: usize = 4096;
 
+/// A FIFO ring buffer holding serial input until the program reads it.
+///
+/// Like on a real brain, input that arrives while the buffer is full is dropped.
+struct InputBuffer {
+    data: VecDeque<u8>,
+}
+
+impl InputBuffer {
+    fn new() -> Self {
+        Self {
+            data: VecDeque::with_capacity(STDIN_BUFFER_SIZE),
+        }
+    }
+
+    /// Adds bytes to the end of the buffer, returning how many didn't fit.
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let free = STDIN_BUFFER_SIZE - self.data.len();
+        let accepted = bytes.len().min(free);
+        self.data.extend(&bytes[..accepted]);
+        bytes.len() - accepted
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        self.data.pop_front()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.front().copied()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// How often buffered output is sent to the frontend while the program is writing to serial.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What happens to serial output when the program writes faster than the link can send it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SerialOverflow {
+    /// Wait for the link to catch up before returning from the write.
+    #[default]
+    Block,
+    /// Drop the output that doesn't fit in the buffer, like a UART that can't keep up.
+    ///
+    /// The whole write still counts as written, so programs don't retry the dropped output.
+    Drop,
+}
+
+/// Emulation settings for the USB link between the brain and the computer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SerialLink {
+    /// The most bytes per second the link can send, or `None` for no limit.
+    pub bandwidth: Option<u32>,
+    pub overflow: SerialOverflow,
+}
+
+/// The highest serial channel number programs can use.
+///
+/// Channel 0 is used by the system and channel 1 by the user program, while tools multiplex
+/// their own streams over the channels above those.
+const MAX_CHANNEL: u32 = 15;
+
+/// The channel that programs' standard input and output go through.
+const USER_CHANNEL: u32 = 1;
+
+/// The input and output buffers for a single serial channel.
+struct SerialChannel {
+    stdout_buffer: Vec<u8>,
+    stdin_buffer: InputBuffer,
+}
+
+impl SerialChannel {
+    fn new() -> Self {
+        Self {
+            stdout_buffer: Vec::with_capacity(STDOUT_BUFFER_SIZE),
+            stdin_buffer: InputBuffer::new(),
+        }
+    }
+
+    fn num_free_bytes(&self) -> usize {
+        STDOUT_BUFFER_SIZE - self.stdout_buffer.len()
+    }
+}
+
 pub struct Serial {
-    stdout_buffer: Cursor<[u8; STDOUT_BUFFER_SIZE]>,
-    stdin_buffer: Cursor<[u8; STDIN_BUFFER_SIZE]>,
+    /// Channels are created the first time they're used.
+    channels: BTreeMap<u32, SerialChannel>,
+    link: SerialLink,
+    /// How many bytes the link could send right now.
+    link_credit: f64,
+    /// When the output was last flushed.
+    last_flush: Duration,
 }
 
 impl Serial {
-    pub fn new() -> Self {
+    pub fn new(link: SerialLink) -> Self {
         Self {
-            stdout_buffer: Cursor::new([0; STDOUT_BUFFER_SIZE]),
-            stdin_buffer: Cursor::new([0; STDIN_BUFFER_SIZE]),
+            channels: BTreeMap::new(),
+            link,
+            link_credit: if link.bandwidth.is_some() {
+                0.0
+            } else {
+                f64::INFINITY
+            },
+            last_flush: Duration::ZERO,
         }
     }
 
+    pub fn overflow(&self) -> SerialOverflow {
+        self.link.overflow
+    }
+
+    /// Returns whether enough time has passed that buffered output should be sent.
+    pub fn flush_due(&self, now: Duration) -> bool {
+        now.saturating_sub(self.last_flush) >= FLUSH_INTERVAL
+    }
+
+    /// Returns how long it will take for the link to be able to send the given number of bytes.
+    pub fn time_until_sendable(&self, bytes: usize) -> Duration {
+        let Some(bandwidth) = self.link.bandwidth else {
+            return Duration::ZERO;
+        };
+        let missing = (bytes.min(STDOUT_BUFFER_SIZE) as f64 - self.link_credit).max(0.0);
+        Duration::from_secs_f64(missing / bandwidth as f64)
+    }
+
+    fn channel(&mut self, channel: u32) -> Result<&mut SerialChannel> {
+        if channel > MAX_CHANNEL {
+            return Err(anyhow!("Invalid channel"));
+        }
+        Ok(self
+            .channels
+            .entry(channel)
+            .or_insert_with(SerialChannel::new))
+    }
+
+    /// Buffers as much of the given output as fits, returning the number of bytes written.
     pub fn write(&mut self, channel: u32, buffer: &[u8]) -> Result<usize> {
-        match channel {
-            1 => {
-                let count = self
-                    .stdout_buffer
-                    .write(buffer)
-                    .context("Failed to write to stdout")?;
-                Ok(count)
+        let channel = self.channel(channel)?;
+        let count = buffer.len().min(channel.num_free_bytes());
+        channel.stdout_buffer.extend_from_slice(&buffer[..count]);
+        Ok(count)
+    }
+
+    /// Queues input for the program to read, returning the number of bytes that were dropped
+    /// because the input buffer is full.
+    pub fn buffer_input(&mut self, channel: u32, buffer: &[u8]) -> Result<usize> {
+        let channel = self.channel(channel)?;
+        let dropped = channel.stdin_buffer.push(buffer);
+        Ok(dropped)
+    }
+
+    pub fn read_byte(&mut self, channel: u32) -> Result<u8> {
+        let channel = self.channel(channel)?;
+        let byte = channel.stdin_buffer.pop();
+        byte.context("No data in stdin buffer")
+    }
+
+    /// Reads up to `len` bytes of input.
+    pub fn read(&mut self, channel: u32, len: usize) -> Result<Vec<u8>> {
+        let channel = self.channel(channel)?;
+        let len = len.min(channel.stdin_buffer.len());
+        Ok(channel.stdin_buffer.data.drain(..len).collect())
+    }
+
+    pub fn peek_byte(&mut self, channel: u32) -> Result<u8> {
+        let channel = self.channel(channel)?;
+        let byte = channel.stdin_buffer.peek();
+        byte.context("No data in stdin buffer")
+    }
+
+    /// Returns the number of bytes of input waiting to be read.
+    pub fn num_available_bytes(&mut self, channel: u32) -> Result<usize> {
+        let channel = self.channel(channel)?;
+        let len = channel.stdin_buffer.len();
+        Ok(len)
+    }
+
+    pub fn num_free_bytes(&mut self, channel: u32) -> Result<usize> {
+        Ok(self.channel(channel)?.num_free_bytes())
+    }
+
+    /// Writes to a serial channel, sending the output when the buffer fills up or hasn't been
+    /// flushed in a while.
+    ///
+    /// Returns the number of bytes written, which is always the length of the buffer unless the
+    /// channel is invalid.
+    pub fn write_through(
+        &mut self,
+        channel: u32,
+        mut buffer: &[u8],
+        clock: &SimClock,
+        mut send: impl FnMut(u32, &[u8]) -> Result<()>,
+    ) -> Result<usize> {
+        let len = buffer.len();
+        loop {
+            let written = self.write(channel, buffer)?;
+            buffer = &buffer[written..];
+
+            let now = clock.now();
+            if buffer.is_empty() {
+                if self.flush_due(now) {
+                    self.flush_to(now, &mut send)?;
+                }
+                return Ok(len);
+            }
+
+            self.flush_to(now, &mut send)?;
+            if self.num_free_bytes(channel)? == 0 {
+                match self.overflow() {
+                    SerialOverflow::Block => {
+                        clock.sleep(self.time_until_sendable(buffer.len()));
+                    }
+                    SerialOverflow::Drop => return Ok(len),
+                }
             }
-            _ => Err(anyhow!("Invalid channel")),
         }
     }
 
-    pub fn write_all(&mut self, channel: u32, buffer: &[u8]) -> Result<()> {
-        match channel {
-            1 => {
-                self.stdout_buffer
-                    .write_all(buffer)
-                    .context("Failed to write to stdout")?;
-                Ok(())
+    /// Sends as much buffered output as the link allows to the frontend.
+    pub fn flush(&mut self, protocol: &mut Protocol, now: Duration) -> Result<()> {
+        self.flush_to(now, |channel, bytes| send_serial(protocol, channel, bytes))
+    }
+
+    /// Sends as much buffered output as the link allows.
+    fn flush_to(
+        &mut self,
+        now: Duration,
+        mut send: impl FnMut(u32, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.refill_credit(now);
+
+        for (&id, channel) in &mut self.channels {
+            let len = take_credit(&mut self.link_credit, channel.stdout_buffer.len());
+            if len > 0 {
+                let bytes = channel.stdout_buffer.drain(..len).collect::<Vec<_>>();
+                send(id, &bytes)?;
             }
-            _ => Err(anyhow!("Invalid channel")),
         }
+        Ok(())
     }
 
-    pub fn buffer_input(&mut self, channel: u32, buffer: &[u8]) -> Result<()> {
-        match channel {
-            1 => {
-                self.stdin_buffer
-                    .write_all(buffer)
-                    .context("Failed to write to stdin")?;
-                Ok(())
-            }
-            _ => Err(anyhow!("Invalid channel")),
+    /// Gives the link the bandwidth it has gained since the last flush.
+    fn refill_credit(&mut self, now: Duration) {
+        if let Some(bandwidth) = self.link.bandwidth {
+            let elapsed = now.saturating_sub(self.last_flush).as_secs_f64();
+            // The link can't save up more than one buffer's worth of bandwidth.
+            self.link_credit =
+                (self.link_credit + elapsed * bandwidth as f64).min(STDOUT_BUFFER_SIZE as f64);
         }
+        self.last_flush = now;
     }
 
-    pub fn read_byte(&mut self, channel: u32) -> Result<u8> {
-        match channel {
-            1 => {
-                let byte = self.peek_byte(channel)?;
-                self.stdin_buffer.seek(SeekFrom::Current(-1)).unwrap();
-                Ok(byte)
-            }
-            _ => Err(anyhow!("Invalid channel")),
+    /// Sends all buffered output to the frontend, regardless of the link's bandwidth.
+    pub fn flush_all(&mut self, protocol: &mut Protocol, now: Duration) -> Result<()> {
+        self.link_credit = f64::INFINITY;
+        self.flush(protocol, now)?;
+        if self.link.bandwidth.is_some() {
+            self.link_credit = 0.0;
         }
+        Ok(())
     }
+}
 
-    pub fn peek_byte(&mut self, channel: u32) -> Result<u8> {
-        match channel {
-            1 => {
-                let pos = self.stdin_buffer.position();
-                if pos == 0 {
-                    bail!("No data in stdin buffer");
+/// Sends serial output to the frontend.
+pub fn send_serial(protocol: &mut Protocol, channel: u32, bytes: &[u8]) -> Result<()> {
+    protocol.send(&Event::Serial(SerialData::new(channel, bytes)))?;
+    Ok(())
+}
+
+/// Takes up to `wanted` bytes worth of credit from the link, returning how many bytes can be sent.
+fn take_credit(credit: &mut f64, wanted: usize) -> usize {
+    let len = (wanted as f64).min(credit.floor()) as usize;
+    *credit -= len as f64;
+    len
+}
+
+// MARK: WASI
+
+/// WASI's file descriptor for stdin.
+const WASI_STDIN: i32 = 0;
+/// WASI's file descriptor for stdout.
+const WASI_STDOUT: i32 = 1;
+/// WASI's file descriptor for stderr.
+const WASI_STDERR: i32 = 2;
+
+/// WASI's error number for a successful call.
+const ERRNO_SUCCESS: i32 = 0;
+/// WASI's error number for a bad memory address.
+const ERRNO_FAULT: i32 = 21;
+
+/// Reads a little-endian `u32` from the program's memory.
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the memory ranges described by a list of WASI iovecs.
+fn iovec_ranges(data: &[u8], iovs: u32, iovs_len: u32) -> Option<Vec<Range<usize>>> {
+    (0..iovs_len as usize)
+        .map(|i| {
+            let iov = (iovs as usize).checked_add(i * 8)?;
+            let buf = read_u32(data, iov)? as usize;
+            let len = read_u32(data, iov + 4)? as usize;
+            let range = buf..buf.checked_add(len)?;
+            data.get(range.clone())?;
+            Some(range)
+        })
+        .collect()
+}
+
+/// How long to wait between checks for input while the program is blocked reading stdin.
+const STDIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Routes the program's WASI stdin, stdout and stderr through the user serial channel.
+///
+/// WASI's `fd_write` and `fd_read` are replaced so that libc's `printf` and `scanf` share buffers
+/// with the SDK's serial functions, and output is flushed and rate limited the same way. Other
+/// file descriptors are passed on to WASI.
+pub fn link_wasi_stdio(linker: &mut Linker<SdkState>, store: &mut Store<SdkState>) -> Result<()> {
+    let wasi_fd_write = linker
+        .get(&mut *store, "wasi_snapshot_preview1", "fd_write")
+        .and_then(Extern::into_func)
+        .context("WASI is missing fd_write")?
+        .typed::<(i32, i32, i32, i32), i32>(&*store)?;
+    let wasi_fd_read = linker
+        .get(&mut *store, "wasi_snapshot_preview1", "fd_read")
+        .and_then(Extern::into_func)
+        .context("WASI is missing fd_read")?
+        .typed::<(i32, i32, i32, i32), i32>(&*store)?;
+
+    linker.allow_shadowing(true);
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_write",
+        move |mut caller: Caller<'_, SdkState>,
+              fd: i32,
+              iovs: i32,
+              iovs_len: i32,
+              nwritten_ptr: i32|
+              -> Result<i32> {
+            if fd != WASI_STDOUT && fd != WASI_STDERR {
+                return wasi_fd_write.call(&mut caller, (fd, iovs, iovs_len, nwritten_ptr));
+            }
+            let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else {
+                return Ok(ERRNO_FAULT);
+            };
+
+            let data = memory.data(&caller);
+            let Some(ranges) = iovec_ranges(data, iovs as u32, iovs_len as u32) else {
+                return Ok(ERRNO_FAULT);
+            };
+            let bytes = ranges
+                .into_iter()
+                .flat_map(|range| &data[range])
+                .copied()
+                .collect::<Vec<_>>();
+
+            let written = caller.data_mut().write_serial(USER_CHANNEL, &bytes)?;
+            let nwritten = (written as u32).to_le_bytes();
+            if memory
+                .write(&mut caller, nwritten_ptr as u32 as usize, &nwritten)
+                .is_err()
+            {
+                return Ok(ERRNO_FAULT);
+            }
+            Ok(ERRNO_SUCCESS)
+        },
+    )?;
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "fd_read",
+        move |mut caller: Caller<'_, SdkState>,
+              fd: i32,
+              iovs: i32,
+              iovs_len: i32,
+              nread_ptr: i32|
+              -> Result<i32> {
+            if fd != WASI_STDIN {
+                return wasi_fd_read.call(&mut caller, (fd, iovs, iovs_len, nread_ptr));
+            }
+            let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else {
+                return Ok(ERRNO_FAULT);
+            };
+            let Some(ranges) = iovec_ranges(memory.data(&caller), iovs as u32, iovs_len as u32)
+            else {
+                return Ok(ERRNO_FAULT);
+            };
+            let wanted = ranges.iter().map(|range| range.len()).sum::<usize>();
+
+            // Reading nothing would look like the end of the file to libc, so wait for input like
+            // a blocking read on a real serial port would. The system task keeps running meanwhile,
+            // but this isn't a yield, so it doesn't count towards lockstep steps.
+            let sdk = caller.data_mut();
+            while wanted > 0 && sdk.serial.num_available_bytes(USER_CHANNEL)? == 0 {
+                // Checked before receiving, so that input sent just before it closed isn't missed.
+                let closed = sdk.protocol.input_closed();
+                sdk.recv_all_commands()?;
+                if closed && sdk.serial.num_available_bytes(USER_CHANNEL)? == 0 {
+                    // There's no more input, so this really is the end of the file.
+                    break;
                 }
-                let idx = pos - 1;
-                let byte = self.stdin_buffer.get_ref()[idx as usize];
-                Ok(byte)
+                sdk.run_system_tick()?;
+                sdk.clock.sleep(STDIN_POLL_INTERVAL);
             }
-            _ => Err(anyhow!("Invalid channel")),
-        }
+            // Time spent waiting for input doesn't count against the program.
+            sdk.feed_watchdog();
+
+            let bytes = caller.data_mut().serial.read(USER_CHANNEL, wanted)?;
+            let data = memory.data_mut(&mut caller);
+            let mut remaining = &bytes[..];
+            for range in ranges {
+                let len = range.len().min(remaining.len());
+                data[range.start..][..len].copy_from_slice(&remaining[..len]);
+                remaining = &remaining[len..];
+            }
+            let nread = (bytes.len() as u32).to_le_bytes();
+            if memory
+                .write(&mut caller, nread_ptr as u32 as usize, &nread)
+                .is_err()
+            {
+                return Ok(ERRNO_FAULT);
+            }
+            Ok(ERRNO_SUCCESS)
+        },
+    )?;
+    linker.allow_shadowing(false);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::clock::ClockMode, *};
+
+    #[test]
+    fn input_buffer_is_fifo() {
+        let mut buffer = InputBuffer::new();
+        assert_eq!(buffer.push(b"ab"), 0);
+        assert_eq!(buffer.push(b"c"), 0);
+        assert_eq!(buffer.peek(), Some(b'a'));
+        assert_eq!(buffer.pop(), Some(b'a'));
+        assert_eq!(buffer.pop(), Some(b'b'));
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.pop(), Some(b'c'));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.peek(), None);
     }
 
-    pub fn num_free_bytes(&mut self, channel: u32) -> Result<usize> {
-        match channel {
-            1 => Ok(STDOUT_BUFFER_SIZE - self.stdout_buffer.position() as usize),
-            _ => Err(anyhow!("Invalid channel")),
-        }
+    #[test]
+    fn input_buffer_drops_overflow() {
+        let mut buffer = InputBuffer::new();
+        assert_eq!(buffer.push(&[0; STDIN_BUFFER_SIZE - 1]), 0);
+        assert_eq!(buffer.push(b"xyz"), 2);
+        assert_eq!(buffer.len(), STDIN_BUFFER_SIZE);
+        assert_eq!(buffer.push(b"!"), 1);
+
+        // The bytes that fit are kept, and the rest of the input is lost.
+        let tail = buffer
+            .data
+            .iter()
+            .rev()
+            .take(2)
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(tail, [b'x', 0]);
     }
 
-    pub fn flush(&mut self, protocol: &mut Protocol) -> Result<()> {
-        if self.stdout_buffer.position() == 0 {
-            return Ok(());
-        }
-        let stdout = std::mem::replace(
-            &mut self.stdout_buffer,
-            Cursor::new([0; STDOUT_BUFFER_SIZE]),
+    #[test]
+    fn channels_have_separate_input() {
+        let mut serial = Serial::new(SerialLink::default());
+        assert_eq!(serial.buffer_input(USER_CHANNEL, b"hello").unwrap(), 0);
+        assert_eq!(serial.buffer_input(2, b"!").unwrap(), 0);
+        assert!(serial.buffer_input(MAX_CHANNEL + 1, b"?").is_err());
+
+        assert_eq!(serial.num_available_bytes(USER_CHANNEL).unwrap(), 5);
+        assert_eq!(serial.read(USER_CHANNEL, 3).unwrap(), b"hel");
+        assert_eq!(serial.peek_byte(USER_CHANNEL).unwrap(), b'l');
+        assert_eq!(serial.read(USER_CHANNEL, 10).unwrap(), b"lo");
+        assert!(serial.read_byte(USER_CHANNEL).is_err());
+        assert_eq!(serial.read_byte(2).unwrap(), b'!');
+        assert_eq!(serial.num_available_bytes(0).unwrap(), 0);
+    }
+
+    fn limited(bandwidth: u32) -> Serial {
+        Serial::new(SerialLink {
+            bandwidth: Some(bandwidth),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn take_credit_rounds_down() {
+        let mut credit = 2.5;
+        assert_eq!(take_credit(&mut credit, 10), 2);
+        assert_eq!(credit, 0.5);
+        assert_eq!(take_credit(&mut credit, 10), 0);
+
+        let mut credit = 100.0;
+        assert_eq!(take_credit(&mut credit, 30), 30);
+        assert_eq!(credit, 70.0);
+
+        let mut credit = f64::INFINITY;
+        assert_eq!(take_credit(&mut credit, 30), 30);
+        assert_eq!(credit, f64::INFINITY);
+    }
+
+    #[test]
+    fn credit_builds_up_over_time() {
+        let mut serial = limited(1000);
+        assert_eq!(serial.link_credit, 0.0);
+        serial.refill_credit(Duration::from_millis(10));
+        assert_eq!(serial.link_credit, 10.0);
+        serial.refill_credit(Duration::from_millis(15));
+        assert_eq!(serial.link_credit, 15.0);
+
+        // Idle time doesn't let the link save up more than one buffer.
+        serial.refill_credit(Duration::from_secs(60));
+        assert_eq!(serial.link_credit, STDOUT_BUFFER_SIZE as f64);
+
+        let mut unlimited = Serial::new(SerialLink::default());
+        unlimited.refill_credit(Duration::from_secs(1));
+        assert_eq!(unlimited.link_credit, f64::INFINITY);
+    }
+
+    #[test]
+    fn time_until_sendable_accounts_for_credit() {
+        let mut serial = limited(1000);
+        assert_eq!(serial.time_until_sendable(100), Duration::from_millis(100));
+        serial.link_credit = 40.0;
+        assert_eq!(serial.time_until_sendable(100), Duration::from_millis(60));
+        assert_eq!(serial.time_until_sendable(10), Duration::ZERO);
+        // Writes bigger than the buffer only have to wait for a full buffer.
+        serial.link_credit = 0.0;
+        assert_eq!(
+            serial.time_until_sendable(STDOUT_BUFFER_SIZE * 2),
+            serial.time_until_sendable(STDOUT_BUFFER_SIZE)
         );
-        let len = stdout.position() as usize;
-        let bytes = &stdout.into_inner()[0..len];
-        protocol.send(&Event::Serial(SerialData::new(1, bytes)))?;
-        Ok(())
+
+        let unlimited = Serial::new(SerialLink::default());
+        assert_eq!(unlimited.time_until_sendable(usize::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn output_is_limited_to_the_buffer() {
+        let mut serial = limited(1000);
+        assert_eq!(serial.write(USER_CHANNEL, &[0; 2000]).unwrap(), 2000);
+        assert_eq!(
+            serial.write(USER_CHANNEL, &[0; 100]).unwrap(),
+            STDOUT_BUFFER_SIZE - 2000
+        );
+        assert_eq!(serial.num_free_bytes(USER_CHANNEL).unwrap(), 0);
+        assert_eq!(serial.num_free_bytes(0).unwrap(), STDOUT_BUFFER_SIZE);
+    }
+
+    /// Writes through a link, returning the number of bytes written and the output that was sent.
+    fn write_through(serial: &mut Serial, clock: &SimClock, len: usize) -> (usize, Vec<u8>) {
+        let mut sent = Vec::new();
+        let written = serial
+            .write_through(USER_CHANNEL, &vec![b'x'; len], clock, |channel, bytes| {
+                assert_eq!(channel, USER_CHANNEL);
+                sent.extend_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        (written, sent)
+    }
+
+    #[test]
+    fn blocked_writes_wait_for_the_link() {
+        let clock = SimClock::new(ClockMode::Stepped);
+        let mut serial = limited(1000);
+
+        let (written, sent) = write_through(&mut serial, &clock, STDOUT_BUFFER_SIZE + 952);
+        assert_eq!(written, STDOUT_BUFFER_SIZE + 952);
+        // The write waited until the link could send the part that didn't fit.
+        assert_eq!(clock.now(), Duration::from_millis(952));
+        assert_eq!(sent.len(), 952);
+        // The rest is buffered, waiting for the link.
+        assert_eq!(serial.num_free_bytes(USER_CHANNEL).unwrap(), 0);
+    }
+
+    #[test]
+    fn dropped_writes_still_count_as_written() {
+        let clock = SimClock::new(ClockMode::Stepped);
+        let mut serial = Serial::new(SerialLink {
+            bandwidth: Some(1000),
+            overflow: SerialOverflow::Drop,
+        });
+
+        let (written, sent) = write_through(&mut serial, &clock, STDOUT_BUFFER_SIZE + 952);
+        assert_eq!(written, STDOUT_BUFFER_SIZE + 952);
+        assert_eq!(clock.now(), Duration::ZERO);
+        assert!(sent.is_empty());
+        assert_eq!(serial.num_free_bytes(USER_CHANNEL).unwrap(), 0);
+
+        // Once the link has caught up, output goes through again.
+        clock.advance(Duration::from_millis(100));
+        let (written, sent) = write_through(&mut serial, &clock, 10);
+        assert_eq!(written, 10);
+        assert_eq!(sent.len(), 100);
+        assert_eq!(serial.num_free_bytes(USER_CHANNEL).unwrap(), 100 - 10);
+    }
+
+    #[test]
+    fn writes_to_invalid_channels_fail() {
+        let clock = SimClock::new(ClockMode::Stepped);
+        let mut serial = Serial::new(SerialLink::default());
+        let result = serial.write_through(MAX_CHANNEL + 1, b"x", &clock, |_, _| Ok(()));
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,446 @@
+use std::{
+    fmt,
+    mem::size_of,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytemuck::{Pod, Zeroable};
+use wasmtime::*;
+
+use crate::sdk::{ExitReason, ProgramExit, SdkState};
+
+use super::JumpTableBuilder;
+
+// MARK: Jump table
+
+/// `vex-sdk` excerpt.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Pod, Zeroable)]
+#[allow(non_camel_case_types)]
+struct time {
+    pub ti_hour: u8,
+    pub ti_min: u8,
+    pub ti_sec: u8,
+    pub ti_hund: u8,
+}
+
+/// `vex-sdk` excerpt.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Pod, Zeroable)]
+#[allow(non_camel_case_types)]
+struct date {
+    pub da_year: u16,
+    pub da_day: u8,
+    pub da_mon: u8,
+}
+
+/// Reads the simulation clock, first accounting for the CPU time the program has used since it
+/// last yielded or read the clock.
+///
+/// This way, a program that times its own loop sees the work it did in that loop.
+fn read_clock(caller: &mut Caller<'_, SdkState>) -> Duration {
+    if let Ok(fuel) = caller.get_fuel() {
+        caller.data_mut().consume_fuel(fuel);
+    }
+    caller.data().clock.read()
+}
+
+pub fn build_system_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
+    // vexSystemTimeGet
+    builder.insert(0x118, move |mut caller: Caller<'_, SdkState>| -> u32 {
+        read_clock(&mut caller).as_millis() as u32
+    });
+
+    // vexGettime
+    builder.insert(
+        0x11c,
+        move |mut caller: Caller<'_, SdkState>, time_ptr: u32| -> Result<()> {
+            let now = read_clock(&mut caller);
+            let now = caller.data().system.wall_clock(now);
+            let time = time {
+                ti_hour: now.hour,
+                ti_min: now.minute,
+                ti_sec: now.second,
+                ti_hund: now.hundredths,
+            };
+            memory.data_mut(&mut caller)[time_ptr as usize..][..size_of::<time>()]
+                .copy_from_slice(bytemuck::bytes_of(&time));
+            Ok(())
+        },
+    );
+
+    // vexGetdate
+    builder.insert(
+        0x120,
+        move |mut caller: Caller<'_, SdkState>, date_ptr: u32| -> Result<()> {
+            let now = read_clock(&mut caller);
+            let now = caller.data().system.wall_clock(now);
+            let date = date {
+                da_year: now.year,
+                da_day: now.day,
+                da_mon: now.month,
+            };
+            memory.data_mut(&mut caller)[date_ptr as usize..][..size_of::<date>()]
+                .copy_from_slice(bytemuck::bytes_of(&date));
+            Ok(())
+        },
+    );
+
+    // vexSystemExitRequest
+    builder.insert(0x130, move || -> Result<()> {
+        // Unwind the program's stack so that the simulator can clean up after it.
+        Err(ProgramExit(ExitReason::Requested).into())
+    });
+
+    // vexSystemHighResTimeGet
+    builder.insert(
+        0x134,
+        move |mut caller: Caller<'_, SdkState>| -> Result<u64> {
+            Ok(read_clock(&mut caller).as_micros() as u64)
+        },
+    );
+
+    // vexSystemPowerupTimeGet
+    builder.insert(0x138, move |mut caller: Caller<'_, SdkState>| -> u64 {
+        let now = read_clock(&mut caller);
+        (caller.data().system.uptime_at_start + now).as_micros() as u64
+    });
+
+    // vexSystemLinkAddrGet
+    builder.insert(0x13c, move || -> u32 {
+        // Hot/cold linking isn't supported, so there's never a linked file.
+        0
+    });
+
+    // vexSystemUsbStatus
+    builder.insert(0x174, move |caller: Caller<'_, SdkState>| -> u32 {
+        caller.data().system.usb_status
+    });
+
+    // vexSystemTimerReinitForRtos
+    builder.insert(0x8c8, move |_priority: u32, _handler: u32| -> i32 {
+        // The simulator drives the RTOS tick itself, so there's no timer to set up.
+        0
+    });
+
+    // vexSystemWatchdogReinitRtos
+    builder.insert(0x8d0, move |mut caller: Caller<'_, SdkState>| -> i32 {
+        caller.data_mut().system.watchdog_enabled = true;
+        0
+    });
+
+    // vexSystemWatchdogGet
+    builder.insert(0x8d4, move |caller: Caller<'_, SdkState>| -> u32 {
+        caller.data().system.watchdog_enabled as u32
+    });
+
+    // vexSystemVersion
+    builder.insert(0x1000, move |caller: Caller<'_, SdkState>| -> u32 {
+        caller.data().system.version.to_u32()
+    });
+}
+
+// MARK: API
+
+/// A VEXos version number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VexosVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub build: u8,
+    pub beta: u8,
+}
+
+impl VexosVersion {
+    /// The version reported when none is chosen.
+    pub const DEFAULT: Self = Self {
+        major: 1,
+        minor: 1,
+        build: 4,
+        beta: 0,
+    };
+
+    /// Packs the version the way `vexSystemVersion` returns it.
+    pub const fn to_u32(self) -> u32 {
+        u32::from_be_bytes([self.major, self.minor, self.build, self.beta])
+    }
+}
+
+impl Default for VexosVersion {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl fmt::Display for VexosVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)?;
+        if self.beta != 0 {
+            write!(f, "-b{}", self.beta)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for VexosVersion {
+    type Err = String;
+
+    /// Parses a version like `1.1.4` or `1.1.4-b2`.
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected `MAJOR.MINOR.BUILD[-bBETA]`, got `{version}`");
+        let (release, beta) = match version.split_once('-') {
+            Some((release, beta)) => {
+                let beta = beta.strip_prefix('b').unwrap_or(beta);
+                (release, beta.parse().map_err(|_| invalid())?)
+            }
+            None => (version, 0),
+        };
+        let mut parts = release.split('.').map(|part| part.parse::<u8>());
+        let (Some(Ok(major)), Some(Ok(minor)), Some(Ok(build)), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            major,
+            minor,
+            build,
+            beta,
+        })
+    }
+}
+
+/// Simulator settings for the system APIs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemOptions {
+    pub version: VexosVersion,
+    /// The date and time when the program starts, in seconds since the Unix epoch.
+    ///
+    /// Defaults to the host's current time.
+    pub start_date: Option<u64>,
+    /// How long the brain had been powered on when the program started.
+    pub uptime_at_start: Duration,
+    /// The value returned by `vexSystemUsbStatus`.
+    pub usb_status: u32,
+}
+
+/// A date and time, as seen by the brain's real-time clock.
+struct WallClock {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    hundredths: u8,
+}
+
+/// The state of the brain's system APIs.
+#[derive(Debug)]
+pub struct System {
+    version: VexosVersion,
+    /// The wall clock time when the program started.
+    start_date: Duration,
+    uptime_at_start: Duration,
+    usb_status: u32,
+    watchdog_enabled: bool,
+}
+
+impl System {
+    pub fn new(options: SystemOptions) -> Self {
+        let start_date = match options.start_date {
+            Some(secs) => Duration::from_secs(secs),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        };
+        Self {
+            version: options.version,
+            start_date,
+            uptime_at_start: options.uptime_at_start,
+            usb_status: options.usb_status,
+            watchdog_enabled: false,
+        }
+    }
+
+    /// Returns the wall clock time after the program has been running for some time.
+    fn wall_clock(&self, since_start: Duration) -> WallClock {
+        let now = self.start_date + since_start;
+        let secs = now.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let secs_of_day = secs % 86400;
+        WallClock {
+            year: year as u16,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            hundredths: (now.subsec_millis() / 10) as u8,
+        }
+    }
+}
+
+/// Converts a number of days since the Unix epoch to a `(year, month, day)` date.
+///
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Converts a `(year, month, day)` date to a number of days since the Unix epoch.
+///
+/// This is Howard Hinnant's `days_from_civil` algorithm.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Returns the number of days in a month of the Gregorian calendar.
+fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parses a `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS` date into seconds since the Unix epoch.
+pub fn parse_date(date: &str) -> Result<u64, String> {
+    let invalid = || format!("expected `YYYY-MM-DD[THH:MM:SS]`, got `{date}`");
+    let (day, time) = date.split_once(['T', ' ']).unwrap_or((date, "00:00:00"));
+
+    let mut day_parts = day.split('-').map(|part| part.parse::<u32>());
+    let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day)), None) = (
+        day_parts.next(),
+        day_parts.next(),
+        day_parts.next(),
+        day_parts.next(),
+    ) else {
+        return Err(invalid());
+    };
+    let mut time_parts = time.split(':').map(|part| part.parse::<u64>());
+    let (Some(Ok(hour)), Some(Ok(minute)), Some(Ok(second)), None) = (
+        time_parts.next(),
+        time_parts.next(),
+        time_parts.next(),
+        time_parts.next(),
+    ) else {
+        return Err(invalid());
+    };
+    if !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year as i64, month as u8) as u32).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+
+    let days = days_from_civil(year as i64, month as u8, day as u8) as u64;
+    Ok(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_days_round_trip() {
+        for days in -1_000_000..1_000_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn civil_from_days_known_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19783), (2024, 3, 1));
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2024-04-25T09:30:00"), Ok(1714037400));
+        assert_eq!(parse_date("2024-04-25 09:30:00"), Ok(1714037400));
+        assert_eq!(parse_date("2024-02-29"), Ok(1709164800));
+        assert_eq!(parse_date("2000-02-29"), Ok(951782400));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        for date in [
+            "",
+            "2024",
+            "2024-04",
+            "2024-04-25-01",
+            "2024-04-25T09:30",
+            "2024-04-25T09:30:00:00",
+            "1969-12-31",
+            "2024-00-01",
+            "2024-13-01",
+            "2024-04-00",
+            "2024-04-31",
+            "2024-02-30",
+            "2023-02-29",
+            "1900-02-29",
+            "2024-04-25T24:00:00",
+            "2024-04-25T09:60:00",
+            "2024-04-25T09:30:60",
+            "2024-O4-25",
+        ] {
+            assert!(parse_date(date).is_err(), "{date:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn vexos_version_round_trip() {
+        for version in ["1.1.4", "1.1.5-b1", "0.0.0", "255.255.255-b255"] {
+            let parsed = version.parse::<VexosVersion>().unwrap();
+            assert_eq!(parsed.to_string(), version);
+        }
+        assert_eq!(
+            "1.1.4-2".parse::<VexosVersion>().unwrap(),
+            VexosVersion {
+                major: 1,
+                minor: 1,
+                build: 4,
+                beta: 2,
+            }
+        );
+        assert_eq!(VexosVersion::DEFAULT.to_u32(), 0x01010400);
+    }
+
+    #[test]
+    fn rejects_invalid_vexos_versions() {
+        for version in [
+            "", "1", "1.1", "1.1.4.0", "1.1.256", "1.1.4-b", "1.1.4-bx", "a.b.c",
+        ] {
+            assert!(
+                version.parse::<VexosVersion>().is_err(),
+                "{version:?} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{collections::VecDeque, mem::size_of};
+
+use bytemuck::{Pod, Zeroable};
+use mint::Point2;
+use vexide_simulator_protocol::TouchEvent;
+use wasmtime::*;
+
+use crate::sdk::SdkState;
+
+use super::JumpTableBuilder;
+
+// MARK: Jump table
+
+/// `vex-sdk` excerpt.
+mod constants {
+    #![allow(non_camel_case_types)]
+    #![allow(non_upper_case_globals)]
+
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct V5_TouchEvent(pub core::ffi::c_uint);
+
+    impl V5_TouchEvent {
+        pub const kTouchEventRelease: Self = Self(0);
+        pub const kTouchEventPress: Self = Self(1);
+        pub const kTouchEventPressAuto: Self = Self(2);
+    }
+}
+
+use constants::V5_TouchEvent;
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Pod, Zeroable)]
+#[allow(non_camel_case_types)]
+struct V5_TouchStatus {
+    pub last_event: u32,
+    pub last_x_pos: i16,
+    pub last_y_pos: i16,
+    pub press_count: i32,
+    pub release_count: i32,
+}
+
+pub fn build_touch_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
+    // vexTouchUserCallbackSet
+    builder.insert(
+        0x960,
+        move |mut caller: Caller<'_, SdkState>, callback: u32| {
+            caller.data_mut().touch.callback = (callback != 0).then_some(callback);
+        },
+    );
+
+    // vexTouchDataGet
+    builder.insert(
+        0x964,
+        move |mut caller: Caller<'_, SdkState>, status_ptr: u32| -> Result<()> {
+            let status = caller.data().touch.status;
+            memory.data_mut(&mut caller)[status_ptr as usize..][..size_of::<V5_TouchStatus>()]
+                .copy_from_slice(bytemuck::bytes_of(&status));
+            Ok(())
+        },
+    );
+}
+
+/// Calls the program's touch callback once for every touch event received since the last call.
+///
+/// The callback is stored in the indirect function table, so it can only be called while the
+/// program is yielding to the SDK.
+pub fn run_touch_callbacks(caller: &mut Caller<'_, SdkState>, table: &Table) -> Result<()> {
+    let Some(callback) = caller.data().touch.callback else {
+        caller.data_mut().touch.pending_callbacks.clear();
+        return Ok(());
+    };
+
+    while let Some((event, point)) = caller.data_mut().touch.pending_callbacks.pop_front() {
+        let Some(Ref::Func(Some(func))) = table.get(&mut *caller, callback) else {
+            anyhow::bail!("Touch callback {callback:#x} is not a function");
+        };
+        let func = func.typed::<(u32, i32, i32), ()>(&*caller)?;
+        func.call(&mut *caller, (event.0, point.x, point.y))?;
+    }
+
+    Ok(())
+}
+
+// MARK: API
+
+/// The state of the brain's touchscreen.
+#[derive(Debug, Default)]
+pub struct Touchscreen {
+    status: V5_TouchStatus,
+    /// Index of the program's touch callback in the indirect function table.
+    callback: Option<u32>,
+    /// Touch events that the program's callback hasn't been called with yet.
+    pending_callbacks: VecDeque<(V5_TouchEvent, Point2<i32>)>,
+}
+
+impl Touchscreen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the touchscreen with a touch event from the frontend.
+    pub fn touch(&mut self, pos: Point2<i32>, event: TouchEvent) {
+        let event = match event {
+            TouchEvent::Press => {
+                self.status.press_count += 1;
+                V5_TouchEvent::kTouchEventPress
+            }
+            TouchEvent::Held => V5_TouchEvent::kTouchEventPressAuto,
+            TouchEvent::Release => {
+                self.status.release_count += 1;
+                V5_TouchEvent::kTouchEventRelease
+            }
+        };
+        self.status.last_event = event.0;
+        self.status.last_x_pos = pos.x as i16;
+        self.status.last_y_pos = pos.y as i16;
+
+        if self.callback.is_some() {
+            self.pending_callbacks.push_back((event, pos));
+        }
+    }
+}
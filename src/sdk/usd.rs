@@ -0,0 +1,727 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use fs_err::{File, OpenOptions};
+use wasmtime::*;
+
+use crate::{protocol::warn_bt, sdk::SdkState};
+
+use super::{clone_c_string, JumpTableBuilder, MemoryExt};
+
+// MARK: Constants
+
+/// `vex-sdk` excerpt.
+mod constants {
+    #![allow(non_camel_case_types)]
+    #![allow(non_upper_case_globals)]
+
+    #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(transparent)]
+    pub struct FRESULT(pub core::ffi::c_uint);
+
+    impl FRESULT {
+        pub const FR_OK: Self = Self(0);
+        pub const FR_DISK_ERR: Self = Self(1);
+        pub const FR_INT_ERR: Self = Self(2);
+        pub const FR_NOT_READY: Self = Self(3);
+        pub const FR_NO_FILE: Self = Self(4);
+        pub const FR_NO_PATH: Self = Self(5);
+        pub const FR_INVALID_NAME: Self = Self(6);
+        pub const FR_DENIED: Self = Self(7);
+        pub const FR_EXIST: Self = Self(8);
+        pub const FR_INVALID_OBJECT: Self = Self(9);
+        pub const FR_WRITE_PROTECTED: Self = Self(10);
+        pub const FR_INVALID_DRIVE: Self = Self(11);
+        pub const FR_NOT_ENABLED: Self = Self(12);
+        pub const FR_NO_FILESYSTEM: Self = Self(13);
+        pub const FR_MKFS_ABORTED: Self = Self(14);
+        pub const FR_TIMEOUT: Self = Self(15);
+        pub const FR_LOCKED: Self = Self(16);
+        pub const FR_NOT_ENOUGH_CORE: Self = Self(17);
+        pub const FR_TOO_MANY_OPEN_FILES: Self = Self(18);
+        pub const FR_INVALID_PARAMETER: Self = Self(19);
+    }
+
+    pub const SEEK_SET: i32 = 0;
+    pub const SEEK_CUR: i32 = 1;
+    pub const SEEK_END: i32 = 2;
+}
+
+use constants::*;
+
+// MARK: Jump table
+
+pub fn build_usd_jump_table(memory: Memory, builder: &mut JumpTableBuilder) {
+    // vexFileMountSD
+    builder.insert(0x7d0, move |caller: Caller<'_, SdkState>| -> u32 {
+        if caller.data().usd.inserted() {
+            FRESULT::FR_OK.0
+        } else {
+            FRESULT::FR_NOT_READY.0
+        }
+    });
+
+    // vexFileDirectoryGet
+    builder.insert(
+        0x7d4,
+        move |mut caller: Caller<'_, SdkState>, path: u32, buffer: u32, len: u32| -> Result<u32> {
+            let path = clone_c_string!(path as usize, from caller using memory);
+            let entries = match caller.data().usd.directory(&path) {
+                Ok(entries) => entries,
+                Err(FileError::Sandbox) => {
+                    warn_bt!(
+                        caller,
+                        "vexFileDirectoryGet: {path:?} is outside of the SD card"
+                    )?;
+                    return Ok(FRESULT::FR_INVALID_NAME.0);
+                }
+                Err(FileError::Result(res)) => return Ok(res.0),
+            };
+
+            // Entries are newline-separated and the list is always null-terminated, even if it is truncated.
+            let mut list = entries.join("\n").into_bytes();
+            list.truncate(len.saturating_sub(1) as usize);
+            list.push(0);
+            if len != 0 {
+                memory.data_mut(&mut caller)[buffer as usize..][..list.len()]
+                    .copy_from_slice(&list);
+            }
+            Ok(FRESULT::FR_OK.0)
+        },
+    );
+
+    let open =
+        move |mut caller: Caller<'_, SdkState>, filename: u32, mode: FileMode| -> Result<u32> {
+            let path = clone_c_string!(filename as usize, from caller using memory);
+            match caller.data_mut().usd.open(&path, mode) {
+                Ok(handle) => Ok(handle),
+                Err(FileError::Sandbox) => {
+                    warn_bt!(
+                        caller,
+                        "Cannot open {path:?} because it is outside of the SD card"
+                    )?;
+                    Ok(0)
+                }
+                Err(FileError::Result(_)) => Ok(0),
+            }
+        };
+
+    // vexFileOpen
+    builder.insert(
+        0x7d8,
+        move |caller: Caller<'_, SdkState>, filename: u32, _mode: u32| -> Result<u32> {
+            open(caller, filename, FileMode::Read)
+        },
+    );
+
+    // vexFileOpenWrite
+    builder.insert(
+        0x7dc,
+        move |caller: Caller<'_, SdkState>, filename: u32| -> Result<u32> {
+            open(caller, filename, FileMode::Append)
+        },
+    );
+
+    // vexFileOpenCreate
+    builder.insert(
+        0x7e0,
+        move |caller: Caller<'_, SdkState>, filename: u32| -> Result<u32> {
+            open(caller, filename, FileMode::Create)
+        },
+    );
+
+    // vexFileClose
+    builder.insert(0x7e4, move |mut caller: Caller<'_, SdkState>, fdp: u32| {
+        caller.data_mut().usd.close(fdp);
+    });
+
+    // vexFileWrite
+    builder.insert(
+        0x7e8,
+        move |mut caller: Caller<'_, SdkState>,
+              buf: u32,
+              size: u32,
+              n_items: u32,
+              fdp: u32|
+              -> Result<i32> {
+            let len = size.saturating_mul(n_items) as usize;
+            let (memory, sdk) = memory.data_and_store_mut(&mut caller);
+            let buffer = &memory[buf as usize..][..len];
+            Ok(sdk.usd.write(fdp, buffer).unwrap_or(-1))
+        },
+    );
+
+    // vexFileSize
+    builder.insert(
+        0x7ec,
+        move |mut caller: Caller<'_, SdkState>, fdp: u32| -> Result<i32> {
+            Ok(caller.data_mut().usd.size(fdp).unwrap_or(-1))
+        },
+    );
+
+    // vexFileSeek
+    builder.insert(
+        0x7f0,
+        move |mut caller: Caller<'_, SdkState>,
+              fdp: u32,
+              offset: u32,
+              whence: i32|
+              -> Result<u32> {
+            let pos = match whence {
+                SEEK_SET => SeekFrom::Start(offset as u64),
+                SEEK_CUR => SeekFrom::Current(offset as i32 as i64),
+                SEEK_END => SeekFrom::End(offset as i32 as i64),
+                _ => return Ok(FRESULT::FR_INVALID_PARAMETER.0),
+            };
+            Ok(match caller.data_mut().usd.seek(fdp, pos) {
+                Ok(()) => FRESULT::FR_OK.0,
+                Err(res) => res.0,
+            })
+        },
+    );
+
+    // vexFileRead
+    builder.insert(
+        0x7f4,
+        move |mut caller: Caller<'_, SdkState>,
+              buf: u32,
+              size: u32,
+              n_items: u32,
+              fdp: u32|
+              -> Result<i32> {
+            let len = size.saturating_mul(n_items) as usize;
+            let (memory, sdk) = memory.data_and_store_mut(&mut caller);
+            let buffer = &mut memory[buf as usize..][..len];
+            Ok(sdk.usd.read(fdp, buffer).unwrap_or(-1))
+        },
+    );
+
+    // vexFileDriveStatus
+    builder.insert(
+        0x7f8,
+        move |caller: Caller<'_, SdkState>, _drive: u32| -> u32 {
+            caller.data().usd.inserted() as u32
+        },
+    );
+
+    // vexFileTell
+    builder.insert(
+        0x7fc,
+        move |mut caller: Caller<'_, SdkState>, fdp: u32| -> Result<i32> {
+            Ok(caller.data_mut().usd.tell(fdp).unwrap_or(-1))
+        },
+    );
+
+    // vexFileSync
+    builder.insert(0x800, move |mut caller: Caller<'_, SdkState>, fdp: u32| {
+        _ = caller.data_mut().usd.sync(fdp);
+    });
+}
+
+// MARK: API
+
+/// Where the SD card is mounted in PROS programs and in the program's WASI environment.
+pub const USD_MOUNT_POINT: &str = "/usd";
+
+/// The number of files that the brain allows to be open at the same time.
+const MAX_OPEN_FILES: usize = 8;
+
+/// How a file on the SD card is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    /// Open an existing file for reading.
+    Read,
+    /// Open a file for writing at its end, creating it if it doesn't exist.
+    Append,
+    /// Create a file for writing, replacing it if it already exists.
+    Create,
+}
+
+/// Reasons an SD card operation can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    /// The path would escape the SD card's root directory.
+    Sandbox,
+    /// The operation failed like it would on a real SD card.
+    Result(FRESULT),
+}
+
+impl From<FRESULT> for FileError {
+    fn from(res: FRESULT) -> Self {
+        Self::Result(res)
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(err: io::Error) -> Self {
+        Self::Result(io_error_result(&err))
+    }
+}
+
+fn io_error_result(err: &io::Error) -> FRESULT {
+    match err.kind() {
+        io::ErrorKind::NotFound => FRESULT::FR_NO_FILE,
+        io::ErrorKind::PermissionDenied => FRESULT::FR_DENIED,
+        io::ErrorKind::AlreadyExists => FRESULT::FR_EXIST,
+        io::ErrorKind::InvalidInput => FRESULT::FR_INVALID_PARAMETER,
+        _ => FRESULT::FR_DISK_ERR,
+    }
+}
+
+struct OpenFile {
+    file: File,
+    /// The file's location on the host.
+    path: PathBuf,
+    writable: bool,
+    append: bool,
+}
+
+/// Faults that can be injected into the simulated SD card.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SdCardFaults {
+    /// The number of bytes that fit on the card. Writes past this are cut short.
+    ///
+    /// This isn't enforced for files the program writes through WASI.
+    pub capacity: Option<u64>,
+    /// Whether the card's write-protect switch is on.
+    pub read_only: bool,
+}
+
+/// A simulated SD card, backed by a directory on the host.
+///
+/// All paths used by the program are confined to the card's root directory.
+#[derive(Default)]
+pub struct SdCard {
+    /// The canonical path of the card's root directory, or `None` if no card is inserted.
+    root: Option<PathBuf>,
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+    faults: SdCardFaults,
+    /// The number of bytes taken up by files on the card.
+    used: u64,
+}
+
+impl SdCard {
+    pub fn new(faults: SdCardFaults) -> Self {
+        Self {
+            faults,
+            ..Default::default()
+        }
+    }
+
+    /// Inserts a card backed by the given directory, or removes the card if `root` is `None`.
+    ///
+    /// Files that were open on the previous card are closed.
+    pub fn set_root(&mut self, root: Option<PathBuf>) -> anyhow::Result<()> {
+        self.files = Default::default();
+        self.root = None;
+        self.used = 0;
+        if let Some(root) = root {
+            let root = fs_err::canonicalize(root).context("Failed to find the SD card root")?;
+            if !root.is_dir() {
+                bail!("The SD card root {} is not a directory", root.display());
+            }
+            self.used = dir_size(&root).context("Failed to measure the SD card's contents")?;
+            self.root = Some(root);
+        }
+        Ok(())
+    }
+
+    /// The directory backing the SD card, if one is inserted.
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    pub fn faults(&self) -> SdCardFaults {
+        self.faults
+    }
+
+    /// Returns whether a card is inserted.
+    pub fn inserted(&self) -> bool {
+        self.root.is_some()
+    }
+
+    /// Converts a path on the card to the corresponding host path.
+    ///
+    /// Paths may optionally start with [`USD_MOUNT_POINT`], which is where the card is mounted in PROS
+    /// programs and WASI.
+    fn resolve(&self, path: &str) -> Result<PathBuf, FileError> {
+        let root = self.root.as_ref().ok_or(FRESULT::FR_NOT_READY)?;
+        let path = match path.strip_prefix(USD_MOUNT_POINT) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => path,
+        };
+
+        let mut resolved = root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => {
+                    resolved.push(part);
+                    // Symlinks inside the card could still point outside of it. Dangling links are
+                    // rejected too, since creating a file through one would follow it.
+                    let is_symlink = resolved
+                        .symlink_metadata()
+                        .is_ok_and(|metadata| metadata.file_type().is_symlink());
+                    if is_symlink {
+                        let target = resolved.canonicalize().map_err(|_| FileError::Sandbox)?;
+                        if !target.starts_with(root) {
+                            return Err(FileError::Sandbox);
+                        }
+                    }
+                }
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return Err(FileError::Sandbox),
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    fn file(&mut self, handle: u32) -> Result<&mut OpenFile, FRESULT> {
+        let index = (handle as usize)
+            .checked_sub(1)
+            .ok_or(FRESULT::FR_INVALID_OBJECT)?;
+        self.files
+            .get_mut(index)
+            .and_then(Option::as_mut)
+            .ok_or(FRESULT::FR_INVALID_OBJECT)
+    }
+
+    /// Opens a file, returning its handle.
+    pub fn open(&mut self, path: &str, mode: FileMode) -> Result<u32, FileError> {
+        let path = self.resolve(path)?;
+        let writable = mode != FileMode::Read;
+        if writable && self.faults.read_only {
+            return Err(FRESULT::FR_WRITE_PROTECTED.into());
+        }
+
+        // A file that's open for writing can't be opened again, and vice versa.
+        let conflict = self
+            .files
+            .iter()
+            .flatten()
+            .any(|open| open.path == path && (writable || open.writable));
+        if conflict {
+            return Err(FRESULT::FR_LOCKED.into());
+        }
+
+        let index = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .ok_or(FRESULT::FR_TOO_MANY_OPEN_FILES)?;
+
+        if mode == FileMode::Create {
+            // The file's old contents are about to be thrown away.
+            let old_len = fs_err::metadata(&path).map_or(0, |metadata| metadata.len());
+            self.used = self.used.saturating_sub(old_len);
+        }
+
+        let file = match mode {
+            FileMode::Read => OpenOptions::new().read(true).open(&path)?,
+            FileMode::Append => OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&path)?,
+            FileMode::Create => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)?,
+        };
+
+        self.files[index] = Some(OpenFile {
+            file,
+            path,
+            writable,
+            append: mode == FileMode::Append,
+        });
+        Ok(index as u32 + 1)
+    }
+
+    pub fn close(&mut self, handle: u32) {
+        if let Some(slot) = (handle as usize)
+            .checked_sub(1)
+            .and_then(|index| self.files.get_mut(index))
+        {
+            *slot = None;
+        }
+    }
+
+    /// Reads from a file, returning the number of bytes read.
+    pub fn read(&mut self, handle: u32, buf: &mut [u8]) -> Result<i32, FRESULT> {
+        let file = self.file(handle)?;
+        let mut total = 0;
+        while total < buf.len() {
+            match file.file.read(&mut buf[total..]) {
+                Ok(0) => break,
+                Ok(read) => total += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(io_error_result(&err)),
+            }
+        }
+        Ok(total as i32)
+    }
+
+    /// Writes to a file, returning the number of bytes written.
+    ///
+    /// If the card is full, only the bytes that fit are written.
+    pub fn write(&mut self, handle: u32, buf: &[u8]) -> Result<i32, FRESULT> {
+        if self.faults.read_only {
+            return Err(FRESULT::FR_WRITE_PROTECTED);
+        }
+        let free = self
+            .faults
+            .capacity
+            .map_or(u64::MAX, |capacity| capacity.saturating_sub(self.used));
+
+        let file = self.file(handle)?;
+        if !file.writable {
+            return Err(FRESULT::FR_DENIED);
+        }
+        let len = file
+            .file
+            .metadata()
+            .map_err(|err| io_error_result(&err))?
+            .len();
+        let pos = file
+            .file
+            .stream_position()
+            .map_err(|err| io_error_result(&err))?;
+        // Files opened for appending always write to the end.
+        let pos = if file.append { len } else { pos };
+
+        // Only bytes that make the file longer take up more space.
+        let overwritten = len.saturating_sub(pos).min(buf.len() as u64);
+        let fits = overwritten.saturating_add(free).min(buf.len() as u64) as usize;
+        file.file
+            .write_all(&buf[..fits])
+            .map_err(|err| io_error_result(&err))?;
+
+        self.used += (fits as u64).saturating_sub(overwritten);
+        Ok(fits as i32)
+    }
+
+    pub fn seek(&mut self, handle: u32, pos: SeekFrom) -> Result<(), FRESULT> {
+        let file = self.file(handle)?;
+        file.file.seek(pos).map_err(|err| io_error_result(&err))?;
+        Ok(())
+    }
+
+    pub fn tell(&mut self, handle: u32) -> Result<i32, FRESULT> {
+        let file = self.file(handle)?;
+        let pos = file
+            .file
+            .stream_position()
+            .map_err(|err| io_error_result(&err))?;
+        Ok(pos as i32)
+    }
+
+    pub fn size(&mut self, handle: u32) -> Result<i32, FRESULT> {
+        let file = self.file(handle)?;
+        let metadata = file.file.metadata().map_err(|err| io_error_result(&err))?;
+        Ok(metadata.len() as i32)
+    }
+
+    pub fn sync(&mut self, handle: u32) -> Result<(), FRESULT> {
+        let file = self.file(handle)?;
+        file.file.sync_data().map_err(|err| io_error_result(&err))?;
+        Ok(())
+    }
+
+    /// Lists the names of the entries in a directory.
+    pub fn directory(&self, path: &str) -> Result<Vec<String>, FileError> {
+        let path = self.resolve(path)?;
+        let mut entries = Vec::new();
+        for entry in fs_err::read_dir(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => FileError::Result(FRESULT::FR_NO_PATH),
+            _ => FileError::from(err),
+        })? {
+            entries.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+/// Adds up the sizes of all the files in a directory and its subdirectories.
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs_err::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    /// A directory that is deleted when the test finishes.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("v5wasm-usd-{name}-{}", std::process::id()));
+            _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn card(root: &Path) -> SdCard {
+        card_with_faults(root, SdCardFaults::default())
+    }
+
+    fn card_with_faults(root: &Path, faults: SdCardFaults) -> SdCard {
+        let mut card = SdCard::new(faults);
+        card.set_root(Some(root.to_path_buf())).unwrap();
+        card
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_card() {
+        let dir = TempDir::new("inside");
+        let card = card(&dir.0);
+        assert_eq!(card.resolve("file.txt"), Ok(dir.0.join("file.txt")));
+        assert_eq!(card.resolve("/file.txt"), Ok(dir.0.join("file.txt")));
+        assert_eq!(card.resolve("/usd/file.txt"), Ok(dir.0.join("file.txt")));
+        assert_eq!(card.resolve("/usd"), Ok(dir.0.clone()));
+        assert_eq!(card.resolve("./a/./b"), Ok(dir.0.join("a/b")));
+        // Only a whole `/usd` component is the mount point.
+        assert_eq!(card.resolve("/usdx/file"), Ok(dir.0.join("usdx/file")));
+    }
+
+    #[test]
+    fn rejects_parent_directories() {
+        let dir = TempDir::new("parent");
+        fs::create_dir(dir.0.join("a")).unwrap();
+        let card = card(&dir.0);
+        for path in [
+            "..",
+            "../file.txt",
+            "/usd/../file.txt",
+            "a/../../file.txt",
+            "a/..",
+        ] {
+            assert_eq!(card.resolve(path), Err(FileError::Sandbox), "{path:?}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_out_of_the_card() {
+        let outside = TempDir::new("symlink-outside");
+        fs::write(outside.0.join("secret.txt"), "secret").unwrap();
+        let dir = TempDir::new("symlink");
+        fs::create_dir(dir.0.join("inner")).unwrap();
+        std::os::unix::fs::symlink(&outside.0, dir.0.join("escape")).unwrap();
+        std::os::unix::fs::symlink(dir.0.join("inner"), dir.0.join("shortcut")).unwrap();
+        let card = card(&dir.0);
+
+        assert_eq!(card.resolve("escape"), Err(FileError::Sandbox));
+        assert_eq!(card.resolve("escape/secret.txt"), Err(FileError::Sandbox));
+        assert_eq!(card.resolve("escape/new.txt"), Err(FileError::Sandbox));
+        // Symlinks that stay inside the card are fine.
+        assert_eq!(
+            card.resolve("shortcut/file.txt"),
+            Ok(dir.0.join("shortcut/file.txt"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_dangling_symlinks() {
+        let outside = TempDir::new("dangling-outside");
+        let dir = TempDir::new("dangling");
+        std::os::unix::fs::symlink(outside.0.join("new.txt"), dir.0.join("link.txt")).unwrap();
+        let mut card = card(&dir.0);
+
+        assert_eq!(card.resolve("link.txt"), Err(FileError::Sandbox));
+        assert_eq!(
+            card.open("link.txt", FileMode::Create),
+            Err(FileError::Sandbox)
+        );
+        assert!(!outside.0.join("new.txt").exists());
+    }
+
+    #[test]
+    fn cuts_writes_short_when_the_card_is_full() {
+        let dir = TempDir::new("full");
+        fs::write(dir.0.join("old.txt"), [0; 4]).unwrap();
+        let mut card = card_with_faults(
+            &dir.0,
+            SdCardFaults {
+                capacity: Some(10),
+                read_only: false,
+            },
+        );
+
+        let file = card.open("new.txt", FileMode::Create).unwrap();
+        assert_eq!(card.write(file, &[1; 4]), Ok(4));
+        assert_eq!(card.write(file, &[2; 4]), Ok(2));
+        assert_eq!(card.write(file, &[3; 4]), Ok(0));
+        // Overwriting a file doesn't take up any more space.
+        card.seek(file, SeekFrom::Start(0)).unwrap();
+        assert_eq!(card.write(file, &[4; 8]), Ok(6));
+        card.close(file);
+        assert_eq!(fs::read(dir.0.join("new.txt")).unwrap(), [4; 6]);
+
+        // Replacing a file frees up the space it took.
+        let file = card.open("old.txt", FileMode::Create).unwrap();
+        assert_eq!(card.write(file, &[5; 8]), Ok(4));
+    }
+
+    #[test]
+    fn refuses_writes_when_write_protected() {
+        let dir = TempDir::new("read-only");
+        fs::write(dir.0.join("file.txt"), "contents").unwrap();
+        let mut card = card_with_faults(
+            &dir.0,
+            SdCardFaults {
+                capacity: None,
+                read_only: true,
+            },
+        );
+
+        for mode in [FileMode::Create, FileMode::Append] {
+            assert_eq!(
+                card.open("file.txt", mode),
+                Err(FRESULT::FR_WRITE_PROTECTED.into())
+            );
+        }
+        let file = card.open("file.txt", FileMode::Read).unwrap();
+        assert_eq!(card.write(file, b"more"), Err(FRESULT::FR_WRITE_PROTECTED));
+        let mut buf = [0; 8];
+        assert_eq!(card.read(file, &mut buf), Ok(8));
+        assert_eq!(&buf, b"contents");
+    }
+
+    #[test]
+    fn fails_without_a_card() {
+        let card = SdCard::new(SdCardFaults::default());
+        assert_eq!(
+            card.resolve("file.txt"),
+            Err(FileError::Result(FRESULT::FR_NOT_READY))
+        );
+    }
+}